@@ -8,5 +8,469 @@
 //! Build script, required as soon as the ``links`` attribute in ``Cargo.toml`` is used. This is to ensure only one
 //! ``ruspiro-allocator`` crate is ever linked into the final binary.
 //!
+//! Beside this it generates the table of bucket sizes the allocator uses. The sizes are taken from the environment
+//! variable ``RUSPIRO_ALLOCATOR_BUCKETS`` or from the ``[buckets]`` section of the TOML file the environment variable
+//! ``RUSPIRO_ALLOCATOR_CONFIG`` points to. Without any of them the power-of-two sizes from 128 Bytes to 2 MB are used.
+//!
+//! With the ``sbrk`` feature the size of the region reserved for ``_sbrk`` is taken from the environment variable
+//! ``RUSPIRO_ALLOCATOR_SBRK_SIZE``, 1 MB by default.
+//!
+//! With the ``emergency-pool`` feature the size of the emergency pool is taken from the environment variable
+//! ``RUSPIRO_ALLOCATOR_EMERGENCY_SIZE``, 4 KB by default.
+//!
+//! With the ``c-abi`` feature the C header declaring the exported allocation functions is generated as well. Its
+//! directory is passed to the build scripts of dependent crates as ``DEP_RUSPIRO_ALLOCATOR_INCLUDE``.
+//!
+
+use std::{env, fs, path::Path};
+
+/// Environment variable containing the bucket sizes
+const BUCKETS_VAR: &str = "RUSPIRO_ALLOCATOR_BUCKETS";
+/// Environment variable containing the path to the allocator configuration file
+const CONFIG_VAR: &str = "RUSPIRO_ALLOCATOR_CONFIG";
+/// Environment variable containing the size of the region reserved for ``_sbrk``
+const SBRK_SIZE_VAR: &str = "RUSPIRO_ALLOCATOR_SBRK_SIZE";
+/// Environment variable containing the size of the emergency pool
+const EMERGENCY_SIZE_VAR: &str = "RUSPIRO_ALLOCATOR_EMERGENCY_SIZE";
+/// The bucket sizes used if nothing else is configured. Each memory block carries its descriptor of 72 Bytes and the
+/// padding for its alignment, so a bucket of 64 Bytes could neither serve an allocation nor a split memory block
+const DEFAULT_BUCKETS: &str = "128..2M/1";
+/// The size of the region reserved for ``_sbrk`` if nothing else is configured
+const DEFAULT_SBRK_SIZE: &str = "1M";
+/// The size of the emergency pool if nothing else is configured
+const DEFAULT_EMERGENCY_SIZE: &str = "4K";
+/// The number of bits following the most significant bit of a size that select its size class. Each power of two is
+/// divided into ``1 << SUB_CLASS_BITS`` size classes. The allocator finds the bucket of a size in constant time as long
+/// as no two neighbouring bucket sizes share a size class
+const SUB_CLASS_BITS: u32 = 3;
+/// The maximum number of buckets. The allocator keeps track of the buckets containing re-usable memory blocks in a
+/// single 64Bit bitmap
+const MAX_BUCKETS: usize = 64;
+/// The C header declaring the functions exported with the ``c-abi`` feature
+const C_HEADER: &str = "\
+/* ruspiro_allocator.h - generated by the build script of the ruspiro-allocator crate, do not edit */
+#ifndef RUSPIRO_ALLOCATOR_H
+#define RUSPIRO_ALLOCATOR_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern \"C\" {
+#endif
+
+/* the alignment of the memory returned by malloc, calloc and realloc */
+#define RUSPIRO_MALLOC_ALIGNMENT 16
+
+void *malloc(size_t size);
+void free(void *ptr);
+void *calloc(size_t count, size_t size);
+void *realloc(void *ptr, size_t size);
+void *aligned_alloc(size_t alignment, size_t size);
+int posix_memalign(void **memptr, size_t alignment, size_t size);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* RUSPIRO_ALLOCATOR_H */
+";
+
+fn main() {
+  println!("cargo:rerun-if-changed=build.rs");
+  println!("cargo:rerun-if-env-changed={}", BUCKETS_VAR);
+  println!("cargo:rerun-if-env-changed={}", CONFIG_VAR);
+  println!("cargo:rerun-if-env-changed={}", SBRK_SIZE_VAR);
+  println!("cargo:rerun-if-env-changed={}", EMERGENCY_SIZE_VAR);
+
+  let sizes = match (env::var(BUCKETS_VAR), env::var(CONFIG_VAR)) {
+    (Ok(spec), _) => parse_spec(&spec).unwrap_or_else(|error| fail(BUCKETS_VAR, &error)),
+    (_, Ok(path)) => {
+      println!("cargo:rerun-if-changed={}", path);
+      let config = fs::read_to_string(&path)
+        .unwrap_or_else(|error| fail(CONFIG_VAR, &format!("unable to read {}: {}", path, error)));
+      parse_config(&config).unwrap_or_else(|error| fail(&path, &error))
+    }
+    _ => parse_spec(DEFAULT_BUCKETS).unwrap(),
+  };
+  if let Err(error) = validate(&sizes) {
+    fail("bucket sizes", &error);
+  }
+
+  let count = sizes.len();
+  let sizes = sizes
+    .iter()
+    .map(|size| format!("0x{:x}", size))
+    .collect::<Vec<_>>()
+    .join(", ");
+  let table = format!(
+    "/// The size of the memory blocks of each bucket, generated by the build script\n\
+     const BUCKET_SIZES: [usize; {}] = [{}];\n\
+     /// The number of bits following the most significant bit of a size that select its size class within the power \
+     of two, generated by the build script\n\
+     const SUB_CLASS_BITS: u32 = {};\n",
+    count, sizes, SUB_CLASS_BITS
+  );
+  let out_dir = env::var("OUT_DIR").unwrap();
+  fs::write(Path::new(&out_dir).join("buckets.rs"), table).unwrap();
+
+  if env::var_os("CARGO_FEATURE_SBRK").is_some() {
+    let constant = size_constant(
+      SBRK_SIZE_VAR,
+      DEFAULT_SBRK_SIZE,
+      "The size of the region reserved for ``_sbrk``",
+      "SBRK_SIZE",
+    );
+    fs::write(Path::new(&out_dir).join("sbrk.rs"), constant).unwrap();
+  }
+
+  if env::var_os("CARGO_FEATURE_EMERGENCY_POOL").is_some() {
+    let constant = size_constant(
+      EMERGENCY_SIZE_VAR,
+      DEFAULT_EMERGENCY_SIZE,
+      "The size of the emergency pool",
+      "EMERGENCY_POOL_SIZE",
+    );
+    fs::write(Path::new(&out_dir).join("emergency.rs"), constant).unwrap();
+  }
+
+  if env::var_os("CARGO_FEATURE_C_ABI").is_some() {
+    let include = Path::new(&out_dir).join("include");
+    fs::create_dir_all(&include).unwrap();
+    fs::write(include.join("ruspiro_allocator.h"), C_HEADER).unwrap();
+    println!("cargo:include={}", include.display());
+  }
+}
+
+/// The definition of a public constant containing the size given in the environment variable or the default size
+fn size_constant(var: &str, default: &str, doc: &str, name: &str) -> String {
+  let spec = env::var(var).unwrap_or_else(|_| default.to_string());
+  let size = parse_size(&spec).unwrap_or_else(|error| panic!("invalid size in {}: {}", var, error));
+  format!(
+    "/// {}, generated by the build script\npub const {}: usize = 0x{:x};\n",
+    doc, name, size
+  )
+}
+
+fn fail(source: &str, error: &str) -> ! {
+  panic!("invalid bucket configuration in {}: {}", source, error);
+}
+
+/// Parse the bucket sizes given either as comma separated list, like ``64,96,128,4K``, or as geometric series
+/// ``min..max/steps`` with the given number of steps for each doubling of the size, like ``64..1M/4``
+fn parse_spec(spec: &str) -> Result<Vec<u64>, String> {
+  match spec.split_once("..") {
+    Some((min, rest)) => {
+      let (max, steps) = rest.split_once('/').unwrap_or((rest, "1"));
+      let steps = steps
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid number of steps '{}'", steps))?;
+      geometric(parse_size(min)?, parse_size(max)?, steps)
+    }
+    None => spec.split(',').map(parse_size).collect(),
+  }
+}
+
+/// Parse the ``[buckets]`` section of the configuration file. It either contains the list of sizes:
+/// ```toml
+/// [buckets]
+/// sizes = [64, 96, 128, "4K"]
+/// ```
+/// or the parameters of a geometric series:
+/// ```toml
+/// [buckets]
+/// min = 64
+/// max = "1M"
+/// steps = 4
+/// ```
+fn parse_config(config: &str) -> Result<Vec<u64>, String> {
+  let mut section = String::new();
+  let (mut sizes, mut min, mut max, mut steps) = (None, None, None, None);
+  // the list of sizes might span multiple lines, so it is collected until the closing bracket
+  let mut pending: Option<String> = None;
+
+  for line in config.lines() {
+    let line = line.split('#').next().unwrap().trim();
+    if let Some(mut value) = pending.take() {
+      value.push_str(line);
+      if value.contains(']') {
+        sizes = Some(parse_array(&value)?);
+      } else {
+        pending = Some(value);
+      }
+      continue;
+    }
+    if line.is_empty() {
+      continue;
+    }
+    if line.starts_with('[') {
+      section = line
+        .trim_matches(|c| c == '[' || c == ']')
+        .trim()
+        .to_string();
+      continue;
+    }
+    if section != "buckets" {
+      continue;
+    }
+    let (key, value) = line
+      .split_once('=')
+      .ok_or_else(|| format!("invalid line '{}'", line))?;
+    let value = value.trim();
+    match key.trim() {
+      "sizes" if value.contains(']') => sizes = Some(parse_array(value)?),
+      "sizes" => pending = Some(value.to_string()),
+      "min" => min = Some(parse_size(value)?),
+      "max" => max = Some(parse_size(value)?),
+      "steps" => steps = Some(parse_size(value)?),
+      key => return Err(format!("unknown key '{}'", key)),
+    }
+  }
+
+  match (sizes, min, max) {
+    (Some(sizes), None, None) => Ok(sizes),
+    (None, Some(min), Some(max)) => geometric(min, max, steps.unwrap_or(1)),
+    _ => {
+      Err("either 'sizes' or 'min' and 'max' need to be given in the [buckets] section".to_string())
+    }
+  }
+}
+
+fn parse_array(value: &str) -> Result<Vec<u64>, String> {
+  value
+    .trim()
+    .trim_start_matches('[')
+    .trim_end_matches(']')
+    .split(',')
+    .filter(|entry| !entry.trim().is_empty())
+    .map(parse_size)
+    .collect()
+}
+
+/// Parse a single size given as decimal or hexadecimal number with an optional ``K`` or ``M`` suffix
+fn parse_size(value: &str) -> Result<u64, String> {
+  let value = value.trim().trim_matches('"').replace('_', "");
+  let (number, factor) = match value.strip_suffix(|c| c == 'K' || c == 'k') {
+    Some(number) => (number, 1024),
+    None => match value.strip_suffix(|c| c == 'M' || c == 'm') {
+      Some(number) => (number, 1024 * 1024),
+      None => (value.as_str(), 1),
+    },
+  };
+  let number = match number.strip_prefix("0x") {
+    Some(hex) => u64::from_str_radix(hex, 16),
+    None => number.parse(),
+  };
+  number
+    .map_err(|_| format!("invalid size '{}'", value))?
+    .checked_mul(factor)
+    .ok_or_else(|| format!("the size '{}' is too large", value))
+}
+
+/// The sizes of a geometric series from ``min`` to ``max``. Each doubling of the size is divided into ``steps``
+/// equally sized steps, so 4 steps give the sizes 64, 80, 96, 112, 128, 160, ...
+fn geometric(min: u64, max: u64, steps: u64) -> Result<Vec<u64>, String> {
+  if !min.is_power_of_two()
+    || !steps.is_power_of_two()
+    || steps > min / 8
+    || steps > 1 << SUB_CLASS_BITS
+  {
+    return Err(format!(
+      "the geometric series requires a power of two as minimum and number of steps not exceeding minimum / 8 and \
+       {}, got {} and {}",
+      1 << SUB_CLASS_BITS,
+      min,
+      steps
+    ));
+  }
+  let mut sizes = Vec::new();
+  let mut base = min;
+  // the series need to stop before the sizes overflow, otherwise the maximum is too large
+  let too_large = || format!("the maximum size {} is too large", max);
+  while base <= max {
+    for step in 0..steps {
+      // the base is a multiple of the number of steps, so each step is exact
+      let size = base
+        .checked_add(step * (base / steps))
+        .ok_or_else(too_large)?;
+      if size > max {
+        break;
+      }
+      sizes.push(size);
+    }
+    base = base.checked_mul(2).ok_or_else(too_large)?;
+  }
+  Ok(sizes)
+}
+
+fn validate(sizes: &[u64]) -> Result<(), String> {
+  if sizes.is_empty() || sizes.len() > MAX_BUCKETS {
+    return Err(format!(
+      "between 1 and {} buckets are supported, got {}",
+      MAX_BUCKETS,
+      sizes.len()
+    ));
+  }
+  if let Some(size) = sizes.iter().find(|&&size| size == 0 || size % 8 != 0) {
+    return Err(format!("the size {} is not a multiple of 8", size));
+  }
+  if let Some(pair) = sizes.windows(2).find(|pair| pair[0] >= pair[1]) {
+    return Err(format!(
+      "the sizes need to be increasing, but {} is followed by {}",
+      pair[0], pair[1]
+    ));
+  }
+  if let Some(pair) = sizes
+    .windows(2)
+    .find(|pair| size_class(pair[0]) == size_class(pair[1]))
+  {
+    return Err(format!(
+      "the sizes {} and {} need to be at least 1/{} of their power of two apart",
+      pair[0],
+      pair[1],
+      1 << SUB_CLASS_BITS
+    ));
+  }
+  Ok(())
+}
+
+/// The size class of the given size, like the bucket allocator calculates it
+fn size_class(size: u64) -> u64 {
+  if size < 1 << SUB_CLASS_BITS {
+    return size;
+  }
+  let msb = u64::BITS - 1 - size.leading_zeros();
+  let sub_class = (size >> (msb - SUB_CLASS_BITS)) & ((1 << SUB_CLASS_BITS) - 1);
+  (((msb - SUB_CLASS_BITS + 1) as u64) << SUB_CLASS_BITS) | sub_class
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_size_suffixes() {
+    assert_eq!(parse_size("64"), Ok(64));
+    assert_eq!(parse_size(" \"4K\" "), Ok(4096));
+    assert_eq!(parse_size("2m"), Ok(2 * 1024 * 1024));
+    assert_eq!(parse_size("0x100"), Ok(256));
+    assert_eq!(parse_size("1_024"), Ok(1024));
+    assert!(parse_size("4G").is_err());
+    assert!(parse_size("").is_err());
+  }
+
+  #[test]
+  fn parse_size_overflow() {
+    assert_eq!(parse_size("0xFFFFFFFFFFFFFFFF"), Ok(u64::MAX));
+    assert!(parse_size("0x10000000000000000").is_err());
+    assert!(parse_size("0xFFFFFFFFFFFFFFK").is_err());
+    assert!(parse_size("0xFFFFFFFFFFFFFM").is_err());
+  }
+
+  #[test]
+  fn parse_spec_list() {
+    assert_eq!(parse_spec("128,192,256,4K"), Ok(vec![128, 192, 256, 4096]));
+    assert!(parse_spec("128,,256").is_err());
+  }
+
+  #[test]
+  fn parse_spec_geometric() {
+    assert_eq!(parse_spec("128..1K"), Ok(vec![128, 256, 512, 1024]));
+    assert_eq!(
+      parse_spec("128..512/4"),
+      Ok(vec![128, 160, 192, 224, 256, 320, 384, 448, 512])
+    );
+    assert!(parse_spec("128..1K/x").is_err());
+  }
+
+  #[test]
+  fn default_buckets() {
+    let sizes = parse_spec(DEFAULT_BUCKETS).unwrap();
+    assert_eq!(sizes.first(), Some(&128));
+    assert_eq!(sizes.last(), Some(&(2 * 1024 * 1024)));
+    assert_eq!(validate(&sizes), Ok(()));
+  }
+
+  #[test]
+  fn geometric_rejects_invalid_parameters() {
+    // minimum not a power of two
+    assert!(geometric(96, 1024, 1).is_err());
+    // number of steps not a power of two
+    assert!(geometric(128, 1024, 3).is_err());
+    // steps closer than 8 Bytes
+    assert!(geometric(64, 1024, 16).is_err());
+    // more steps than size classes within a power of two
+    assert!(geometric(1024, 1 << 20, 16).is_err());
+    assert_eq!(geometric(1024, 1024, 8).map(|sizes| sizes.len()), Ok(1));
+  }
+
+  #[test]
+  fn geometric_overflow() {
+    assert!(parse_spec("128..0x8000000000000000/1").is_err());
+    assert!(geometric(128, u64::MAX, 1).is_err());
+    assert_eq!(geometric(1 << 62, 1 << 62, 1), Ok(vec![1 << 62]));
+  }
+
+  #[test]
+  fn validate_sizes() {
+    assert_eq!(validate(&[128, 256]), Ok(()));
+    assert!(validate(&[]).is_err());
+    assert!(validate(&vec![128; MAX_BUCKETS + 1]).is_err());
+    assert!(validate(&[128, 260]).is_err());
+    assert!(validate(&[0, 128]).is_err());
+    assert!(validate(&[256, 128]).is_err());
+    assert!(validate(&[128, 128]).is_err());
+    // 128 and 136 share the size class [128, 144)
+    assert!(validate(&[128, 136]).is_err());
+    assert_eq!(validate(&[128, 144]), Ok(()));
+  }
+
+  #[test]
+  fn size_classes() {
+    assert_eq!(size_class(0), 0);
+    assert_eq!(size_class(7), 7);
+    assert_eq!(size_class(8), 8);
+    assert_eq!(size_class(15), 15);
+    assert_eq!(size_class(16), 16);
+    assert_eq!(size_class(128), size_class(143));
+    assert_eq!(size_class(143) + 1, size_class(144));
+    assert_eq!(size_class(255) + 1, size_class(256));
+  }
+
+  #[test]
+  fn parse_config_sizes() {
+    let config = "\
+[package]
+sizes = [1]
+
+[buckets]
+# a list spanning multiple lines
+sizes = [128, 192, # the small ones
+  256, \"4K\"]
+";
+    assert_eq!(parse_config(config), Ok(vec![128, 192, 256, 4096]));
+  }
+
+  #[test]
+  fn parse_config_geometric() {
+    let config = "[buckets]\nmin = 128\nmax = \"1K\"\nsteps = 2\n";
+    assert_eq!(
+      parse_config(config),
+      Ok(vec![128, 192, 256, 384, 512, 768, 1024])
+    );
+    assert_eq!(
+      parse_config("[buckets]\nmin = 128\nmax = 512\n"),
+      Ok(vec![128, 256, 512])
+    );
+  }
 
-fn main() {}
+  #[test]
+  fn parse_config_errors() {
+    assert!(parse_config("").is_err());
+    assert!(parse_config("[buckets]\nmin = 128\n").is_err());
+    assert!(parse_config("[buckets]\nsizes = [128]\nmin = 128\nmax = 256\n").is_err());
+    assert!(parse_config("[buckets]\ncount = 4\n").is_err());
+    assert!(parse_config("[buckets]\nsizes\n").is_err());
+    assert!(parse_config("[buckets]\nmin = 128\nmax = \"0xFFFFFFFFFFFFFFK\"\n").is_err());
+  }
+}
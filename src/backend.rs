@@ -0,0 +1,305 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Allocator Backends
+//!
+//! The HEAP is managed by one of several backends. Each of them implements the [Backend] trait and the global
+//! allocator only dispatches to the backend chosen by cargo feature. Without any backend feature the bucket allocator
+//! is used. If several backends are chosen the ``tlsf`` backend is preferred over the ``buddy`` backend.
+//!
+
+use crate::memory;
+use core::alloc::Layout;
+use core::fmt;
+use core::ptr::NonNull;
+
+/// Statistics about the HEAP usage of the active allocator backend
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeapStats {
+  /// The memory taken into account by the backend so far, in Bytes
+  pub heap_size: usize,
+  /// The memory occupied by live allocations including the administrative data of each memory block, in Bytes
+  pub used: usize,
+  /// The number of live allocations
+  pub allocations: usize,
+}
+
+/// The reasons an allocation with [try_alloc] might fail
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AllocError {
+  /// The HEAP has not enough free memory left to serve the allocation
+  OutOfMemory,
+  /// The alignment is larger than the allocator backend could serve
+  InvalidAlignment(usize),
+  /// The HEAP region has not been set up yet
+  HeapNotInitialised,
+  /// The free memory block at the given address has been corrupted
+  Corruption(usize),
+}
+
+impl fmt::Display for AllocError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      AllocError::OutOfMemory => write!(f, "out of heap memory"),
+      AllocError::InvalidAlignment(align) => {
+        write!(f, "alignment of {:#x} Bytes is not supported", align)
+      }
+      AllocError::HeapNotInitialised => write!(f, "the heap is not initialised"),
+      AllocError::Corruption(address) => {
+        write!(f, "corrupted free memory block at {:#x}", address)
+      }
+    }
+  }
+}
+
+/// The operations each allocator backend need to provide
+pub(crate) trait Backend: Sync {
+  /// The largest alignment the backend could serve
+  const MAX_ALIGN: usize;
+
+  /// Allocate memory of the given size and alignment. Fails if the HEAP is exhausted or a corrupted free memory block
+  /// has been found
+  fn try_alloc(&self, size: usize, align: usize) -> Result<NonNull<u8>, AllocError>;
+
+  /// Allocate memory of the given size and alignment. Returns a null pointer if the HEAP is exhausted, corruptions
+  /// of the HEAP are not recoverable
+  fn alloc(&self, size: usize, align: usize) -> *mut u8 {
+    match reclaiming(size, || self.try_alloc(size, align)) {
+      Ok(ptr) => ptr.as_ptr(),
+      Err(error @ AllocError::Corruption(_)) => panic!("{}", error),
+      Err(_) => core::ptr::null_mut(),
+    }
+  }
+
+  /// Free the memory at the given address
+  ///
+  /// # Safety
+  /// The memory need to be allocated from this backend and not freed yet
+  unsafe fn free(&self, ptr: *mut u8);
+
+  /// The number of bytes usable at the given address of an allocated memory
+  ///
+  /// # Safety
+  /// The memory need to be allocated from this backend and not freed yet
+  unsafe fn usable_size(&self, ptr: *mut u8) -> usize;
+
+  /// Resize the memory at the given address allocated with the given layout. The memory is kept in place as long as
+  /// the new size fits into its usable size, otherwise it is moved to a new memory block. Returns a null pointer if
+  /// the HEAP is exhausted, the memory at the given address is kept untouched in this case
+  ///
+  /// # Safety
+  /// The memory need to be allocated from this backend with the given layout and not freed yet
+  unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    if new_size <= self.usable_size(ptr) {
+      return ptr;
+    }
+    let new_ptr = self.alloc(new_size, layout.align());
+    if !new_ptr.is_null() {
+      core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+      self.free(ptr);
+    }
+    new_ptr
+  }
+
+  /// The current statistics of the HEAP usage
+  fn stats(&self) -> HeapStats;
+}
+
+/// Run the given allocation of the given size. With the ``memory-pressure`` feature the registered callbacks are asked
+/// to release memory and the allocation is retried before it fails because the HEAP is exhausted
+#[inline]
+#[cfg_attr(not(feature = "memory-pressure"), allow(unused_variables))]
+fn reclaiming<F>(size: usize, mut alloc: F) -> Result<NonNull<u8>, AllocError>
+where
+  F: FnMut() -> Result<NonNull<u8>, AllocError>,
+{
+  let result = alloc();
+  #[cfg(feature = "memory-pressure")]
+  if result == Err(AllocError::OutOfMemory) {
+    return crate::pressure::reclaim(size, alloc);
+  }
+  result
+}
+
+/// Panics once it is dropped while unwinding, which aborts. It is forgotten if the operation guarded returns
+struct AbortOnUnwind;
+
+impl Drop for AbortOnUnwind {
+  fn drop(&mut self) {
+    panic!("a panic must not unwind out of the allocator");
+  }
+}
+
+/// Run the given operation of the allocator and abort if it panics and unwinds. Unwinding out of the global allocator
+/// or a function called from C is undefined behaviour. The message of the panic is reported before aborting
+#[inline]
+pub(crate) fn abort_on_unwind<R, F: FnOnce() -> R>(f: F) -> R {
+  let guard = AbortOnUnwind;
+  let result = f();
+  core::mem::forget(guard);
+  result
+}
+
+/// Account for memory of the given size handed out at the given address, a null pointer is ignored. Which of the
+/// parameters of the accounting functions are used depends on the features enabled
+#[inline]
+#[allow(unused_variables)]
+pub(crate) fn allocated(ptr: *mut u8, size: usize) {
+  #[cfg(feature = "leak-tracking")]
+  if !ptr.is_null() {
+    crate::leak::track(ptr, size);
+  }
+  #[cfg(feature = "measure")]
+  if !ptr.is_null() {
+    crate::measure::allocated(size);
+  }
+  #[cfg(feature = "watermarks")]
+  crate::watermark::check();
+}
+
+/// Account for the memory at the given address about to be given back. This need to happen before the memory is
+/// handed back to the backend, as another core is able to re-use its address right afterwards
+#[inline]
+#[allow(unused_variables)]
+pub(crate) fn freeing(ptr: *mut u8) {
+  #[cfg(feature = "leak-tracking")]
+  crate::leak::untrack(ptr);
+}
+
+/// Account for the memory of the given size given back
+#[inline]
+#[allow(unused_variables)]
+pub(crate) fn freed(size: usize) {
+  #[cfg(feature = "measure")]
+  crate::measure::freed(size);
+  #[cfg(feature = "watermarks")]
+  crate::watermark::check();
+}
+
+/// The accounting state of memory about to be re-allocated, see [reallocating]
+pub(crate) struct Reallocation {
+  /// The tag the memory has been tracked with
+  #[cfg(feature = "leak-tracking")]
+  tag: crate::leak::LeakTag,
+}
+
+/// Account for the memory at the given address about to be re-allocated. Like [freeing] this need to happen before
+/// the memory is handed to the backend, the returned state is passed to [reallocated] afterwards
+#[inline]
+#[allow(unused_variables)]
+pub(crate) fn reallocating(ptr: *mut u8) -> Reallocation {
+  Reallocation {
+    #[cfg(feature = "leak-tracking")]
+    tag: crate::leak::untrack(ptr),
+  }
+}
+
+/// Account for the memory of the given size at the given address moved to the new address with the new size. A null
+/// pointer as new address keeps the memory at the given address, as it is left untouched in this case
+#[inline]
+#[allow(unused_variables)]
+pub(crate) fn reallocated(
+  reallocation: Reallocation,
+  ptr: *mut u8,
+  size: usize,
+  new_ptr: *mut u8,
+  new_size: usize,
+) {
+  #[cfg(feature = "leak-tracking")]
+  if new_ptr.is_null() {
+    crate::leak::retrack(ptr, size, reallocation.tag);
+  } else {
+    crate::leak::retrack(new_ptr, new_size, reallocation.tag);
+  }
+  #[cfg(feature = "measure")]
+  if !new_ptr.is_null() {
+    crate::measure::freed(size);
+    crate::measure::allocated(new_size);
+  }
+  #[cfg(feature = "watermarks")]
+  crate::watermark::check();
+}
+
+#[cfg(all(feature = "buddy", not(feature = "tlsf")))]
+use crate::buddy::BuddyBackend as ActiveBackend;
+#[cfg(not(any(feature = "buddy", feature = "tlsf")))]
+use crate::memory::BucketBackend as ActiveBackend;
+#[cfg(feature = "tlsf")]
+use crate::tlsf::TlsfBackend as ActiveBackend;
+
+/// The backend managing the HEAP, chosen by cargo feature
+pub(crate) static BACKEND: ActiveBackend = ActiveBackend;
+
+/// The largest alignment the backend managing the HEAP could serve
+pub(crate) const MAX_ALIGN: usize = ActiveBackend::MAX_ALIGN;
+
+/// Get the current statistics of the HEAP usage of the active allocator backend
+///
+/// # Example
+/// ```ignore
+/// let stats = ruspiro_allocator::heap_stats();
+/// println!("{} allocations occupy {} of {} Bytes", stats.allocations, stats.used, stats.heap_size);
+/// ```
+pub fn heap_stats() -> HeapStats {
+  BACKEND.stats()
+}
+
+/// Allocate memory for the given layout and report why this failed instead of returning a null pointer like the
+/// global allocator. This allows to free some memory and retry or to degrade gracefully if the HEAP is exhausted.
+/// The returned memory covers the whole usable size of the memory block, which might be larger than requested.
+///
+/// The memory need to be freed with the global allocator, for example by turning it into a ``Box``. With the
+/// ``memory-pressure`` feature the registered callbacks already had their chance to release memory once this reports
+/// [AllocError::OutOfMemory].
+///
+/// # Example
+/// ```ignore
+/// use core::alloc::Layout;
+/// use ruspiro_allocator::AllocError;
+///
+/// match ruspiro_allocator::try_alloc(Layout::from_size_align(0x1000, 16).unwrap()) {
+///   Ok(memory) => println!("got {} Bytes", memory.len()),
+///   Err(AllocError::OutOfMemory) => drop_caches(),
+///   Err(error) => panic!("{}", error),
+/// }
+/// ```
+pub fn try_alloc(layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+  if memory::heap_limit() <= memory::heap_bottom() {
+    return Err(AllocError::HeapNotInitialised);
+  }
+  if layout.align() > MAX_ALIGN {
+    return Err(AllocError::InvalidAlignment(layout.align()));
+  }
+  let memory = reclaiming(layout.size(), || {
+    BACKEND.try_alloc(layout.size(), layout.align())
+  })?;
+  // the whole usable size is handed out, so it is accounted as allocated as well
+  let size = unsafe { BACKEND.usable_size(memory.as_ptr()) };
+  allocated(memory.as_ptr(), size);
+  let slice = core::ptr::slice_from_raw_parts_mut(memory.as_ptr(), size);
+  Ok(unsafe { NonNull::new_unchecked(slice) })
+}
+
+/// The number of bytes usable at the given address of an allocated memory. As the size of each memory block is
+/// rounded up this is often more than requested and the whole usable size could be used by the caller.
+///
+/// # Safety
+/// The memory need to be allocated from this allocator and not freed yet
+///
+/// # Example
+/// ```ignore
+/// let memory: Vec<u8> = Vec::with_capacity(100);
+/// let usable = unsafe { ruspiro_allocator::usable_size(memory.as_ptr() as *mut u8) };
+/// assert!(usable >= 100);
+/// ```
+pub unsafe fn usable_size(ptr: *mut u8) -> usize {
+  #[cfg(feature = "emergency-pool")]
+  if crate::emergency::contains(ptr) {
+    return crate::emergency::usable_size(ptr);
+  }
+  BACKEND.usable_size(ptr)
+}
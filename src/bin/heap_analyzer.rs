@@ -0,0 +1,215 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Heap Snapshot Analyzer
+//!
+//! Host tool to analyze heap snapshots written with ``ruspiro_allocator::write_snapshot``. It reports the
+//! fragmentation of the heap, the occupancy of each bucket and the largest run of free memory.
+//!
+//! Build and run it on the host machine with the ``std`` feature:
+//! ```text
+//! cargo run --features std --target x86_64-unknown-linux-gnu --bin heap-analyzer -- <snapshot file | ->
+//! ```
+//!
+
+use ruspiro_allocator::{SnapshotError, SnapshotReader, SnapshotRecord};
+use std::collections::HashSet;
+use std::io::Read;
+
+/// The statistics of a single bucket
+#[derive(Default, Clone)]
+struct BucketStats {
+  live_blocks: u64,
+  live_bytes: u64,
+  requested_bytes: u64,
+  free_blocks: u64,
+  free_bytes: u64,
+  listed_blocks: u64,
+}
+
+fn main() {
+  let source = match std::env::args().nth(1) {
+    Some(source) => source,
+    None => {
+      eprintln!("usage: heap-analyzer <snapshot file | ->");
+      std::process::exit(1);
+    }
+  };
+
+  let mut data = Vec::new();
+  let read = if source == "-" {
+    std::io::stdin().read_to_end(&mut data)
+  } else {
+    std::fs::File::open(&source).and_then(|mut file| file.read_to_end(&mut data))
+  };
+  if let Err(error) = read {
+    eprintln!("unable to read {}: {}", source, error);
+    std::process::exit(1);
+  }
+
+  if let Err(error) = analyze(&data) {
+    eprintln!("unable to analyze {}: {}", source, error);
+    std::process::exit(1);
+  }
+}
+
+fn analyze(data: &[u8]) -> Result<(), SnapshotError> {
+  let snapshot = SnapshotReader::new(data)?;
+  let mut buckets = vec![BucketStats::default(); snapshot.bucket_count()];
+  // the current and the largest run of adjacent free memory blocks as (address, size)
+  let mut free_run = (0, 0);
+  let mut largest_free_run = (0, 0);
+  let mut aborted_at = None;
+  let (mut free_blocks, mut free_bytes) = (0, 0);
+  let (mut quarantined_blocks, mut quarantined_bytes) = (0, 0);
+
+  // the quarantined memory blocks are listed after the HEAP walk, but they shall not be counted as free ones
+  let mut quarantined = HashSet::new();
+  for record in snapshot.records() {
+    if let SnapshotRecord::Quarantined { address } = record? {
+      quarantined.insert(address);
+    }
+  }
+
+  for record in snapshot.records() {
+    let record = record?;
+    match record {
+      SnapshotRecord::Block {
+        address,
+        size,
+        bucket,
+        req_size,
+        ..
+      } => {
+        let stats = buckets.get_mut(bucket as usize);
+        if record.is_free() && quarantined.contains(&address) {
+          free_run = (0, 0);
+          quarantined_blocks += 1;
+          quarantined_bytes = add(quarantined_bytes, size)?;
+        } else if record.is_free() {
+          if add(free_run.0, free_run.1)? != address || free_run.1 == 0 {
+            free_run = (address, 0);
+          }
+          free_run.1 = add(free_run.1, size)?;
+          if free_run.1 > largest_free_run.1 {
+            largest_free_run = free_run;
+          }
+          free_blocks += 1;
+          free_bytes = add(free_bytes, size)?;
+          if let Some(stats) = stats {
+            stats.free_blocks += 1;
+            stats.free_bytes = add(stats.free_bytes, size)?;
+          }
+        } else {
+          free_run = (0, 0);
+          if let Some(stats) = stats {
+            stats.live_blocks += 1;
+            stats.live_bytes = add(stats.live_bytes, size)?;
+            stats.requested_bytes = add(stats.requested_bytes, req_size)?;
+          }
+        }
+      }
+      SnapshotRecord::FreeEntry { bucket, .. } => {
+        if let Some(stats) = buckets.get_mut(bucket as usize) {
+          stats.listed_blocks += 1;
+        }
+      }
+      SnapshotRecord::WalkAborted { address } => aborted_at = Some(address),
+      SnapshotRecord::Quarantined { .. } | SnapshotRecord::End { .. } => (),
+    }
+  }
+
+  // the reader ensures the end of the heap does not lie before its start
+  let heap_size = snapshot.heap_end - snapshot.heap_start;
+  let live_bytes = buckets
+    .iter()
+    .try_fold(0, |sum, stats| add(sum, stats.live_bytes))?;
+  let requested_bytes = buckets
+    .iter()
+    .try_fold(0, |sum, stats| add(sum, stats.requested_bytes))?;
+
+  println!(
+    "heap {:#010x} - {:#010x} ({} bytes), snapshot version {}",
+    snapshot.heap_start, snapshot.heap_end, heap_size, snapshot.version
+  );
+  if let Some(address) = aborted_at {
+    println!(
+      "WARNING: corrupted memory block at {:#010x}, the heap could not be walked any further",
+      address
+    );
+  }
+  println!(
+    "live:  {} blocks, {} bytes ({} bytes requested, {} bytes lost to bucket rounding)",
+    buckets.iter().map(|stats| stats.live_blocks).sum::<u64>(),
+    live_bytes,
+    requested_bytes,
+    live_bytes.saturating_sub(requested_bytes)
+  );
+  println!(
+    "free:  {} blocks, {} bytes ({:.1}% of the heap)",
+    free_blocks,
+    free_bytes,
+    percent(free_bytes, heap_size)
+  );
+  if quarantined_blocks > 0 {
+    println!(
+      "quarantined: {} blocks, {} bytes ({:.1}% of the heap)",
+      quarantined_blocks,
+      quarantined_bytes,
+      percent(quarantined_bytes, heap_size)
+    );
+  }
+  println!(
+    "largest free run: {} bytes at {:#010x}",
+    largest_free_run.1, largest_free_run.0
+  );
+  println!(
+    "fragmentation: {:.1}% (share of free memory outside the largest free run)",
+    percent(free_bytes.saturating_sub(largest_free_run.1), free_bytes)
+  );
+
+  println!();
+  println!(
+    "{:>6} {:>10} {:>8} {:>12} {:>8} {:>12} {:>8} {:>10}",
+    "bucket", "size", "live", "live bytes", "free", "free bytes", "listed", "occupancy"
+  );
+  for (bucket, stats) in buckets.iter().enumerate() {
+    if stats.live_blocks + stats.free_blocks + stats.listed_blocks == 0 {
+      continue;
+    }
+    let size = snapshot
+      .bucket_size(bucket)
+      .map_or_else(|| "dynamic".to_string(), |size| format!("{:#x}", size));
+    println!(
+      "{:>6} {:>10} {:>8} {:>12} {:>8} {:>12} {:>8} {:>9.1}%",
+      bucket,
+      size,
+      stats.live_blocks,
+      stats.live_bytes,
+      stats.free_blocks,
+      stats.free_bytes,
+      stats.listed_blocks,
+      percent(stats.live_blocks, stats.live_blocks + stats.free_blocks)
+    );
+  }
+
+  Ok(())
+}
+
+/// Add two sizes taken from the snapshot. They only overflow if the snapshot is corrupted
+fn add(a: u64, b: u64) -> Result<u64, SnapshotError> {
+  a.checked_add(b).ok_or(SnapshotError::Corrupted)
+}
+
+/// The share of the value in the total in percent
+fn percent(value: u64, total: u64) -> f64 {
+  if total == 0 {
+    0.0
+  } else {
+    value as f64 * 100.0 / total as f64
+  }
+}
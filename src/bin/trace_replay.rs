@@ -0,0 +1,264 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Allocation Trace Replay
+//!
+//! Host tool to replay an allocation trace written with ``ruspiro_allocator::write_trace`` against the allocator
+//! logic running on a simulated heap. It reports the peak heap usage, the fragmentation at the end of the replay and
+//! the hit rate of each bucket. As the allocator is compiled into this tool, different bucket configurations and
+//! policies could be compared by rebuilding it with different settings.
+//!
+//! Build and run it on the host machine with the ``std`` and ``trace`` features:
+//! ```text
+//! cargo run --features std,trace --target x86_64-unknown-linux-gnu --bin trace-replay -- <trace file | -> [heap size]
+//! ```
+//!
+
+use ruspiro_allocator::simulation::SimulatedHeap;
+use ruspiro_allocator::{SnapshotReader, SnapshotRecord, TraceError, TraceOp, TraceReader};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+
+/// The default size of the simulated heap
+const DEFAULT_HEAP_SIZE: usize = 256 * 1024 * 1024;
+
+/// The statistics of a single bucket
+#[derive(Default, Clone)]
+struct BucketStats {
+  /// allocations served from re-usable memory blocks of this bucket
+  hits: u64,
+  /// allocations served from the end of the heap
+  misses: u64,
+}
+
+/// The statistics of the replay
+#[derive(Default)]
+struct ReplayStats {
+  allocs: u64,
+  frees: u64,
+  unknown_frees: u64,
+  failed_allocs: u64,
+  requested_bytes: usize,
+  peak_requested_bytes: usize,
+  peak_heap_usage: usize,
+  buckets: Vec<BucketStats>,
+}
+
+/// The reasons a trace could not be replayed
+#[derive(Debug)]
+enum ReplayError {
+  /// The trace could not be read
+  Trace(TraceError),
+  /// The allocation of the given address has an alignment that is not a power of two
+  InvalidAlignment { address: usize, align: usize },
+  /// The given address has been allocated again before it has been freed
+  DuplicateAddress(usize),
+}
+
+impl From<TraceError> for ReplayError {
+  fn from(error: TraceError) -> Self {
+    ReplayError::Trace(error)
+  }
+}
+
+impl fmt::Display for ReplayError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ReplayError::Trace(error) => write!(f, "{}", error),
+      ReplayError::InvalidAlignment { address, align } => write!(
+        f,
+        "allocation trace contains the allocation of {:#x} with the invalid alignment {:#x}",
+        address, align
+      ),
+      ReplayError::DuplicateAddress(address) => write!(
+        f,
+        "allocation trace allocates {:#x} again before it has been freed",
+        address
+      ),
+    }
+  }
+}
+
+fn main() {
+  let mut args = std::env::args().skip(1);
+  let source = match args.next() {
+    Some(source) => source,
+    None => {
+      eprintln!("usage: trace-replay <trace file | -> [heap size]");
+      std::process::exit(1);
+    }
+  };
+  let heap_size = match args.next().map(|size| size.parse::<usize>()) {
+    None => DEFAULT_HEAP_SIZE,
+    Some(Ok(size)) => size,
+    Some(Err(error)) => {
+      eprintln!("invalid heap size: {}", error);
+      std::process::exit(1);
+    }
+  };
+
+  let mut data = Vec::new();
+  let read = if source == "-" {
+    std::io::stdin().read_to_end(&mut data)
+  } else {
+    std::fs::File::open(&source).and_then(|mut file| file.read_to_end(&mut data))
+  };
+  if let Err(error) = read {
+    eprintln!("unable to read {}: {}", source, error);
+    std::process::exit(1);
+  }
+
+  if let Err(error) = replay(&data, heap_size) {
+    eprintln!("unable to replay {}: {}", source, error);
+    std::process::exit(1);
+  }
+}
+
+fn replay(data: &[u8], heap_size: usize) -> Result<(), ReplayError> {
+  let trace = TraceReader::new(data)?;
+  let mut heap = SimulatedHeap::new(heap_size);
+  let mut stats = ReplayStats::default();
+  // the recorded payload addresses mapped to the simulated ones together with the requested size
+  let mut live = HashMap::new();
+
+  for event in trace {
+    let event = event?;
+    match event.op {
+      TraceOp::Alloc | TraceOp::AllocPage => {
+        // the allocator requires a power of two alignment, the page size of alloc_page included
+        if !event.align.is_power_of_two() {
+          return Err(ReplayError::InvalidAlignment {
+            address: event.address,
+            align: event.align,
+          });
+        }
+        if live.contains_key(&event.address) {
+          return Err(ReplayError::DuplicateAddress(event.address));
+        }
+        let used = heap.used();
+        let address = if event.op == TraceOp::Alloc {
+          heap.alloc(event.size, event.align)
+        } else {
+          heap.alloc_page(event.size / event.align, event.align)
+        };
+        if address.is_null() {
+          stats.failed_allocs += 1;
+          continue;
+        }
+        stats.allocs += 1;
+        // the bucket is taken from the simulated heap as it might be configured differently than the recorded one
+        let bucket = unsafe { heap.bucket(address) };
+        if stats.buckets.len() <= bucket {
+          stats.buckets.resize(bucket + 1, BucketStats::default());
+        }
+        // the heap only grows if the allocation could not be served from a re-usable memory block
+        if heap.used() > used {
+          stats.buckets[bucket].misses += 1;
+        } else {
+          stats.buckets[bucket].hits += 1;
+        }
+        stats.requested_bytes += event.size;
+        stats.peak_requested_bytes = stats.peak_requested_bytes.max(stats.requested_bytes);
+        stats.peak_heap_usage = stats.peak_heap_usage.max(heap.used());
+        live.insert(event.address, (address, event.size));
+      }
+      TraceOp::Free => match live.remove(&event.address) {
+        Some((address, size)) => {
+          unsafe { heap.free(address) };
+          stats.frees += 1;
+          stats.requested_bytes -= size;
+        }
+        // the memory has been allocated before the trace was recorded
+        None => stats.unknown_frees += 1,
+      },
+    }
+  }
+
+  report(&heap, &stats);
+  Ok(())
+}
+
+fn report(heap: &SimulatedHeap, stats: &ReplayStats) {
+  println!(
+    "replayed {} allocations and {} frees on a simulated heap of {} bytes",
+    stats.allocs,
+    stats.frees,
+    heap.size()
+  );
+  if stats.failed_allocs > 0 {
+    println!(
+      "WARNING: {} allocations failed on the simulated heap",
+      stats.failed_allocs
+    );
+  }
+  if stats.unknown_frees > 0 {
+    println!(
+      "{} frees of memory allocated before the trace was recorded have been skipped",
+      stats.unknown_frees
+    );
+  }
+  println!(
+    "peak heap usage: {} bytes for at most {} bytes requested",
+    stats.peak_heap_usage, stats.peak_requested_bytes
+  );
+
+  // take a snapshot of the simulated heap to calculate the fragmentation at the end of the replay
+  let mut snapshot = Vec::new();
+  let _ = heap.write_snapshot(|chunk| {
+    snapshot.extend_from_slice(chunk);
+    Ok::<(), ()>(())
+  });
+  if let Ok(snapshot) = SnapshotReader::new(&snapshot) {
+    let (mut free_bytes, mut free_run, mut largest_free_run) = (0, 0, 0);
+    for record in snapshot.records().flatten() {
+      if let SnapshotRecord::Block { size, .. } = record {
+        if record.is_free() {
+          free_bytes += size;
+          free_run += size;
+          largest_free_run = largest_free_run.max(free_run);
+        } else {
+          free_run = 0;
+        }
+      }
+    }
+    println!(
+      "final heap usage: {} bytes, {} bytes free, fragmentation {:.1}%",
+      heap.used(),
+      free_bytes,
+      percent(free_bytes - largest_free_run, free_bytes)
+    );
+  }
+
+  println!();
+  println!(
+    "{:>6} {:>10} {:>10} {:>10}",
+    "bucket", "hits", "misses", "hit rate"
+  );
+  for (bucket, bucket_stats) in stats.buckets.iter().enumerate() {
+    let total = bucket_stats.hits + bucket_stats.misses;
+    if total == 0 {
+      continue;
+    }
+    println!(
+      "{:>6} {:>10} {:>10} {:>9.1}%",
+      bucket,
+      bucket_stats.hits,
+      bucket_stats.misses,
+      percent(bucket_stats.hits, total)
+    );
+  }
+}
+
+/// The share of the value in the total in percent
+fn percent(value: u64, total: u64) -> f64 {
+  if total == 0 {
+    0.0
+  } else {
+    value as f64 * 100.0 / total as f64
+  }
+}
@@ -0,0 +1,294 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Buddy Allocator
+//!
+//! With the ``buddy`` feature the HEAP is managed as a binary buddy system instead of the bucket allocator. Each memory
+//! block has a power-of-two size and is aligned to its size. Splitting a memory block gives two halves, the buddies.
+//! Once a memory block is freed while its buddy is free as well both are merged into the larger memory block again.
+//! This bounds the fragmentation and gives naturally aligned memory for page sized allocations.
+//!
+//! The HEAP is taken into account in chunks of [CHUNK_ORDER] once the free memory blocks could not serve an
+//! allocation. So memory is only touched once it is required, like with the bucket allocator.
+//!
+//! Each memory block starts with a header. Live memory blocks store the address of this header in front of the
+//! payload, so the header is found from the payload address when freeing. The state of the buddy system is guarded by
+//! a spin lock.
+//!
+
+use crate::backend::{AllocError, Backend, HeapStats};
+use crate::lock::SpinLock;
+use crate::memory;
+use core::ptr::NonNull;
+
+/// The order of the smallest memory block, 64 Bytes
+pub(crate) const MIN_ORDER: u32 = 6;
+
+/// The order of the largest memory block, 1 GB
+pub(crate) const MAX_ORDER: u32 = 30;
+
+/// The order of the chunks the buddy system takes from the HEAP when it needs to grow, 2 MB
+pub(crate) const CHUNK_ORDER: u32 = 21;
+
+/// The magic identifier of a live memory block
+const BUDDY_MAGIC: u32 = 0xB0DD_1E5A;
+
+/// The magic identifier of a free memory block
+const BUDDY_FREE_MAGIC: u32 = 0xB0DD_F4EE;
+
+/// The size of the header of a live memory block. It also leaves room to store the header address in front of the
+/// payload
+const HEADER_SIZE: usize = 16;
+
+/// The number of orders from [MIN_ORDER] to [MAX_ORDER]
+const ORDERS: usize = (MAX_ORDER - MIN_ORDER + 1) as usize;
+
+/// The header located at the start of each memory block. The links are only used while the memory block is free
+#[repr(C)]
+struct BlockHeader {
+  magic: u32,
+  order: u32,
+  prev: usize,
+  next: usize,
+}
+
+/// The state of the buddy system
+struct BuddySystem {
+  /// The address of the first memory block
+  start: usize,
+  /// The address following the last memory block
+  top: usize,
+  /// The first free memory block of each order
+  free: [usize; ORDERS],
+  /// Bitmap of the orders that contain free memory blocks
+  non_empty: u32,
+  /// The memory occupied by live memory blocks
+  used: usize,
+  /// The number of live memory blocks
+  allocations: usize,
+}
+
+static BUDDY: SpinLock<BuddySystem> = SpinLock::new(BuddySystem {
+  start: 0,
+  top: 0,
+  free: [0; ORDERS],
+  non_empty: 0,
+  used: 0,
+  allocations: 0,
+});
+
+/// Allocate memory of the given size and alignment
+pub(crate) fn try_alloc(size: usize, align: usize) -> Result<NonNull<u8>, AllocError> {
+  // the payload follows the header and is aligned as requested. As each memory block is aligned to its size this
+  // gives the requested alignment for the payload
+  let offset = align.max(HEADER_SIZE);
+  let order = offset
+    .checked_add(size)
+    .and_then(order_for)
+    .ok_or(AllocError::OutOfMemory)?;
+
+  let mut buddy = BUDDY.lock();
+  if buddy.start == 0 {
+    let start = align_up(memory::heap_bottom(), 1 << MIN_ORDER);
+    buddy.start = start;
+    buddy.top = start;
+  }
+  let block = match buddy.take(order) {
+    Err(AllocError::OutOfMemory) if buddy.grow(order) => buddy.take(order)?,
+    block => block?,
+  };
+  buddy.used += 1 << order;
+  buddy.allocations += 1;
+  drop(buddy);
+
+  let header = unsafe { &mut *(block as *mut BlockHeader) };
+  header.magic = BUDDY_MAGIC;
+  header.order = order;
+  let payload = block + offset;
+  unsafe { *((payload - core::mem::size_of::<usize>()) as *mut usize) = block };
+  Ok(unsafe { NonNull::new_unchecked(payload as *mut u8) })
+}
+
+/// Free the memory of the given payload address and merge it with its buddies
+pub(crate) fn free(address: *mut u8) {
+  let block = unsafe { *((address as usize - core::mem::size_of::<usize>()) as *const usize) };
+  let header = unsafe { &*(block as *const BlockHeader) };
+  assert!(header.magic == BUDDY_MAGIC);
+  let order = header.order;
+  let mut buddy = BUDDY.lock();
+  buddy.used -= 1 << order;
+  buddy.allocations -= 1;
+  buddy.insert(block, order);
+}
+
+/// Forget about the whole buddy system, the next allocation starts over at the bottom of the HEAP
+#[cfg(feature = "std")]
+pub(crate) fn reset() {
+  let mut buddy = BUDDY.lock();
+  buddy.start = 0;
+  buddy.top = 0;
+  buddy.free = [0; ORDERS];
+  buddy.non_empty = 0;
+  buddy.used = 0;
+  buddy.allocations = 0;
+}
+
+/// The buddy system as backend of the global allocator
+pub(crate) struct BuddyBackend;
+
+impl Backend for BuddyBackend {
+  /// The payload of the largest memory block need to follow its header
+  const MAX_ALIGN: usize = 1 << (MAX_ORDER - 1);
+
+  fn try_alloc(&self, size: usize, align: usize) -> Result<NonNull<u8>, AllocError> {
+    try_alloc(size, align)
+  }
+
+  unsafe fn free(&self, ptr: *mut u8) {
+    free(ptr)
+  }
+
+  unsafe fn usable_size(&self, ptr: *mut u8) -> usize {
+    let block = *((ptr as usize - core::mem::size_of::<usize>()) as *const usize);
+    let header = &*(block as *const BlockHeader);
+    block + (1 << header.order) - ptr as usize
+  }
+
+  fn stats(&self) -> HeapStats {
+    let buddy = BUDDY.lock();
+    HeapStats {
+      heap_size: buddy.top - buddy.start,
+      used: buddy.used,
+      allocations: buddy.allocations,
+    }
+  }
+}
+
+impl BuddySystem {
+  /// Take a free memory block of the given order. If there is none a larger one is split, while the upper halves
+  /// are kept as free memory blocks of the smaller orders
+  fn take(&mut self, order: u32) -> Result<usize, AllocError> {
+    let candidates = self.non_empty >> (order - MIN_ORDER);
+    if candidates == 0 {
+      return Err(AllocError::OutOfMemory);
+    }
+    let mut block_order = order + candidates.trailing_zeros();
+    let block = self.free[(block_order - MIN_ORDER) as usize];
+    let header = unsafe { &*(block as *const BlockHeader) };
+    if header.magic != BUDDY_FREE_MAGIC || header.order != block_order {
+      return Err(AllocError::Corruption(block));
+    }
+    self.remove(block, block_order);
+    while block_order > order {
+      block_order -= 1;
+      self.push(block + (1 << block_order), block_order);
+    }
+    Ok(block)
+  }
+
+  /// Take the next chunk from the HEAP into the buddy system that is large enough for a memory block of the given
+  /// order. Returns false if the HEAP is exhausted
+  fn grow(&mut self, order: u32) -> bool {
+    // take a whole chunk if possible, otherwise only what is required for this memory block
+    let fits = |chunk_order: u32| {
+      let chunk = align_up(self.top, 1 << chunk_order);
+      matches!(chunk.checked_add(1 << chunk_order), Some(end) if end <= memory::heap_limit())
+    };
+    let chunk_order = match order.max(CHUNK_ORDER) {
+      chunk_order if fits(chunk_order) => chunk_order,
+      _ if fits(order) => order,
+      _ => return false,
+    };
+    let chunk = align_up(self.top, 1 << chunk_order);
+
+    // the memory between the current top and the aligned chunk becomes free memory blocks of the largest orders
+    // possible. The chunk is added afterwards as its buddy might be part of this gap
+    let mut block = self.top;
+    self.top = chunk;
+    while block < chunk {
+      let mut block_order = block.trailing_zeros().min(MAX_ORDER);
+      while block + (1 << block_order) > chunk {
+        block_order -= 1;
+      }
+      self.insert(block, block_order);
+      block += 1 << block_order;
+    }
+    self.top = chunk + (1 << chunk_order);
+    self.insert(chunk, chunk_order);
+    true
+  }
+
+  /// Insert a free memory block and merge it with its buddy as long as this is free as well
+  fn insert(&mut self, mut block: usize, mut order: u32) {
+    while order < MAX_ORDER {
+      let buddy = block ^ (1 << order);
+      // the buddy need to be part of the buddy system. As all memory blocks inside are aligned to their size the
+      // buddy address always points to the header of a memory block then
+      if buddy < self.start || buddy + (1 << order) > self.top {
+        break;
+      }
+      let header = unsafe { &*(buddy as *const BlockHeader) };
+      if header.magic != BUDDY_FREE_MAGIC || header.order != order {
+        break;
+      }
+      self.remove(buddy, order);
+      block = block.min(buddy);
+      order += 1;
+    }
+    self.push(block, order);
+  }
+
+  /// Push the memory block to the list of free memory blocks of its order
+  fn push(&mut self, block: usize, order: u32) {
+    let index = (order - MIN_ORDER) as usize;
+    let header = unsafe { &mut *(block as *mut BlockHeader) };
+    header.magic = BUDDY_FREE_MAGIC;
+    header.order = order;
+    header.prev = 0;
+    header.next = self.free[index];
+    if header.next != 0 {
+      unsafe { (*(header.next as *mut BlockHeader)).prev = block };
+    }
+    self.free[index] = block;
+    self.non_empty |= 1 << index;
+  }
+
+  /// Remove the memory block from the list of free memory blocks of its order
+  fn remove(&mut self, block: usize, order: u32) {
+    let index = (order - MIN_ORDER) as usize;
+    let header = unsafe { &mut *(block as *mut BlockHeader) };
+    if header.prev != 0 {
+      unsafe { (*(header.prev as *mut BlockHeader)).next = header.next };
+    } else {
+      self.free[index] = header.next;
+    }
+    if header.next != 0 {
+      unsafe { (*(header.next as *mut BlockHeader)).prev = header.prev };
+    }
+    if self.free[index] == 0 {
+      self.non_empty &= !(1 << index);
+    }
+    header.magic = 0;
+  }
+}
+
+/// The order of the smallest memory block holding the given size
+fn order_for(size: usize) -> Option<u32> {
+  let order = size
+    .checked_next_power_of_two()?
+    .trailing_zeros()
+    .max(MIN_ORDER);
+  if order > MAX_ORDER {
+    None
+  } else {
+    Some(order)
+  }
+}
+
+fn align_up(address: usize, align: usize) -> usize {
+  (address + align - 1) & !(align - 1)
+}
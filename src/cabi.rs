@@ -0,0 +1,141 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # C ABI
+//!
+//! With the ``c-abi`` feature the allocation functions of the C standard library are exported, so C libraries linked
+//! into the binary share the HEAP with the Rust code. The build script generates the header ``ruspiro_allocator.h``
+//! declaring them. Its directory is passed to dependent build scripts as ``DEP_RUSPIRO_ALLOCATOR_INCLUDE``.
+//!
+//! As there is no ``errno`` in a baremetal environment failures are only reported by the return values.
+//!
+
+use crate::backend::{self, Backend, BACKEND};
+use core::alloc::Layout;
+use core::ffi::c_void;
+
+/// The alignment of the memory returned by ``malloc``, ``calloc`` and ``realloc``. This is the alignment of the
+/// largest fundamental type on Aarch64
+const MALLOC_ALIGN: usize = 16;
+
+/// Error code of ``posix_memalign`` for an invalid alignment
+const EINVAL: i32 = 22;
+
+/// Error code of ``posix_memalign`` if the HEAP is exhausted
+const ENOMEM: i32 = 12;
+
+/// Allocate memory of the given size and alignment. Any failure results in a null pointer
+fn allocate(size: usize, align: usize) -> *mut c_void {
+  backend::abort_on_unwind(|| match Layout::from_size_align(size, align) {
+    Ok(layout) => crate::try_alloc(layout).map_or(core::ptr::null_mut(), |memory| {
+      memory.as_ptr() as *mut c_void
+    }),
+    Err(_) => core::ptr::null_mut(),
+  })
+}
+
+/// Allocate memory of the given size. Returns a null pointer if the HEAP is exhausted
+#[cfg_attr(not(any(test, doctest, feature = "std")), no_mangle)]
+pub extern "C" fn malloc(size: usize) -> *mut c_void {
+  allocate(size, MALLOC_ALIGN)
+}
+
+/// Free memory allocated with any of the exported functions. Freeing a null pointer does nothing
+///
+/// # Safety
+/// The memory need to be allocated from this allocator and not freed yet
+#[cfg_attr(not(any(test, doctest, feature = "std")), no_mangle)]
+pub unsafe extern "C" fn free(ptr: *mut c_void) {
+  if !ptr.is_null() {
+    backend::abort_on_unwind(|| {
+      // the requested size of the memory is not known, its whole usable size is given back
+      let size = BACKEND.usable_size(ptr as *mut u8);
+      backend::freeing(ptr as *mut u8);
+      BACKEND.free(ptr as *mut u8);
+      backend::freed(size);
+    })
+  }
+}
+
+/// Allocate zeroed memory for an array of the given number of elements of the given size. Returns a null pointer if
+/// the size of the array overflows or the HEAP is exhausted
+#[cfg_attr(not(any(test, doctest, feature = "std")), no_mangle)]
+pub extern "C" fn calloc(count: usize, size: usize) -> *mut c_void {
+  let size = match count.checked_mul(size) {
+    Some(size) => size,
+    None => return core::ptr::null_mut(),
+  };
+  let ptr = allocate(size, MALLOC_ALIGN);
+  if !ptr.is_null() {
+    unsafe { core::ptr::write_bytes(ptr as *mut u8, 0, size) };
+  }
+  ptr
+}
+
+/// Resize the memory at the given address. A null pointer allocates new memory, a size of 0 frees the memory and
+/// returns a null pointer. If the HEAP is exhausted a null pointer is returned and the memory is kept untouched
+///
+/// # Safety
+/// The memory need to be allocated from this allocator and not freed yet
+#[cfg_attr(not(any(test, doctest, feature = "std")), no_mangle)]
+pub unsafe extern "C" fn realloc(ptr: *mut c_void, size: usize) -> *mut c_void {
+  if ptr.is_null() {
+    return malloc(size);
+  }
+  if size == 0 {
+    free(ptr);
+    return core::ptr::null_mut();
+  }
+  backend::abort_on_unwind(|| {
+    // the requested size of the memory is not known, but all of its usable size could be moved
+    let old_size = BACKEND.usable_size(ptr as *mut u8);
+    let layout = Layout::from_size_align_unchecked(old_size, MALLOC_ALIGN);
+    let reallocation = backend::reallocating(ptr as *mut u8);
+    let new_ptr = BACKEND.realloc(ptr as *mut u8, layout, size);
+    // the memory is given back with its whole usable size by ``free``, so it is accounted this way as well
+    let new_size = if new_ptr.is_null() {
+      0
+    } else {
+      BACKEND.usable_size(new_ptr)
+    };
+    backend::reallocated(reallocation, ptr as *mut u8, old_size, new_ptr, new_size);
+    new_ptr as *mut c_void
+  })
+}
+
+/// Allocate memory of the given size and alignment. Returns a null pointer if the alignment is not a power of two or
+/// the HEAP is exhausted
+#[cfg_attr(not(any(test, doctest, feature = "std")), no_mangle)]
+pub extern "C" fn aligned_alloc(align: usize, size: usize) -> *mut c_void {
+  allocate(size, align)
+}
+
+/// Allocate memory of the given size and alignment and store its address at the given location. Returns ``EINVAL``
+/// if the alignment is not a power of two multiple of the pointer size or larger than the allocator could serve and
+/// ``ENOMEM`` if the HEAP is exhausted. The location is not touched in these cases
+///
+/// # Safety
+/// The given location need to be valid to store a pointer
+#[cfg_attr(not(any(test, doctest, feature = "std")), no_mangle)]
+pub unsafe extern "C" fn posix_memalign(
+  memptr: *mut *mut c_void,
+  align: usize,
+  size: usize,
+) -> i32 {
+  if !align.is_power_of_two()
+    || align < core::mem::size_of::<*mut c_void>()
+    || align > backend::MAX_ALIGN
+  {
+    return EINVAL;
+  }
+  let ptr = allocate(size, align);
+  if ptr.is_null() {
+    return ENOMEM;
+  }
+  *memptr = ptr;
+  0
+}
@@ -0,0 +1,30 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # CPU Helper
+//!
+
+/// The number of cores of the Raspberry Pi
+#[allow(dead_code)]
+pub(crate) const CORE_COUNT: usize = 4;
+
+/// The ID of the core executing this code
+#[cfg(all(target_arch = "aarch64", target_os = "none"))]
+#[allow(dead_code)]
+pub(crate) fn core_id() -> usize {
+  let mpidr: u64;
+  unsafe { core::arch::asm!("mrs {}, mpidr_el1", out(reg) mpidr) };
+  (mpidr & 0x3) as usize
+}
+
+/// The ID of the core executing this code. Outside the Raspberry Pi there is no core ID available, so all code is
+/// treated as running on the first core
+#[cfg(not(all(target_arch = "aarch64", target_os = "none")))]
+#[allow(dead_code)]
+pub(crate) fn core_id() -> usize {
+  0
+}
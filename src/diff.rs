@@ -0,0 +1,135 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Heap Diffing
+//!
+//! Each memory block carries the generation of the HEAP it has been allocated in. [mark] advances the generation and
+//! returns it as token, [diff] walks the HEAP and lists the live memory blocks allocated since. This allows tests to
+//! assert that a piece of code does not leave any allocations behind.
+//!
+//! Only the memory blocks of the bucket allocator carry their generation, so diffing is not available with the
+//! ``buddy`` or ``tlsf`` feature.
+//!
+
+use crate::memory::{self, MemoryDescriptor, GENERATION, MM_MAGIC};
+use core::fmt;
+use core::sync::atomic::Ordering;
+
+/// The maximum number of memory blocks listed in a [HeapDiff]. Any further memory block is only counted.
+pub const MAX_DIFF_BLOCKS: usize = 16;
+
+/// Opaque token of the state of the HEAP returned by [mark]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HeapMark(u32);
+
+/// A live memory block allocated since the mark
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DiffBlock {
+  /// The address handed out by the allocator
+  pub address: usize,
+  /// The size requested
+  pub size: usize,
+}
+
+/// The live memory blocks allocated since a mark
+#[derive(Copy, Clone, Debug)]
+pub struct HeapDiff {
+  /// The number of live memory blocks allocated since the mark, this might be more than listed in this diff
+  pub block_count: usize,
+  /// The sum of the sizes requested for all of them
+  pub bytes: usize,
+  /// The address of the corrupted memory block the HEAP walk stopped at. Any memory block behind it is not listed
+  pub aborted_at: Option<usize>,
+  blocks: [DiffBlock; MAX_DIFF_BLOCKS],
+}
+
+impl HeapDiff {
+  /// Returns whether all memory allocated since the mark has been freed. This could not be told if the HEAP walk
+  /// stopped at a corrupted memory block, so such a diff is never empty
+  pub fn is_empty(&self) -> bool {
+    self.block_count == 0 && self.aborted_at.is_none()
+  }
+
+  /// Iterate over the memory blocks listed in this diff
+  pub fn blocks(&self) -> impl Iterator<Item = &DiffBlock> {
+    self.blocks[..self.block_count.min(MAX_DIFF_BLOCKS)].iter()
+  }
+
+  fn add(&mut self, descriptor: &MemoryDescriptor) {
+    let size = descriptor.req_size;
+    if let Some(slot) = self.blocks.get_mut(self.block_count) {
+      *slot = DiffBlock {
+        address: descriptor.payload_addr,
+        size,
+      };
+    }
+    self.block_count += 1;
+    self.bytes += size;
+  }
+}
+
+impl fmt::Display for HeapDiff {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(
+      f,
+      "{} memory blocks with {} Bytes allocated since the mark",
+      self.block_count, self.bytes
+    )?;
+    for block in self.blocks() {
+      writeln!(f, "  {:#010x} {:>#12x}", block.address, block.size)?;
+    }
+    if self.block_count > MAX_DIFF_BLOCKS {
+      writeln!(f, "  ... {} more", self.block_count - MAX_DIFF_BLOCKS)?;
+    }
+    if let Some(address) = self.aborted_at {
+      writeln!(
+        f,
+        "  corrupted memory block at {:#010x}, the HEAP could not be walked any further",
+        address
+      )?;
+    }
+    Ok(())
+  }
+}
+
+/// Mark the current state of the HEAP. Any memory allocated afterwards is listed by [diff] with the returned token as
+/// long as it is not freed.
+///
+/// # Example
+/// ```ignore
+/// let mark = ruspiro_allocator::mark();
+/// handle_request(&mut server);
+/// let diff = ruspiro_allocator::diff(mark);
+/// assert!(diff.is_empty(), "{}", diff);
+/// ```
+pub fn mark() -> HeapMark {
+  HeapMark(GENERATION.fetch_add(1, Ordering::AcqRel).wrapping_add(1))
+}
+
+/// List the live memory blocks allocated since the given mark. Memory re-allocated in place keeps the generation it
+/// has been allocated in.
+///
+/// This walks the whole HEAP and gives only a consistent picture if no other core is allocating or freeing memory at
+/// the same time. A corrupted memory block stops the walk and is reported as [HeapDiff::aborted_at].
+pub fn diff(mark: HeapMark) -> HeapDiff {
+  let mut diff = HeapDiff {
+    block_count: 0,
+    bytes: 0,
+    aborted_at: None,
+    blocks: [DiffBlock::default(); MAX_DIFF_BLOCKS],
+  };
+  let walk = memory::walk_heap(|_, descriptor| {
+    let generation = descriptor.generation;
+    // the generations are compared with wrapping arithmetic, so the wrap around of the counter does not matter
+    if descriptor.magic == MM_MAGIC && generation.wrapping_sub(mark.0) < u32::MAX / 2 {
+      diff.add(descriptor);
+    }
+    true
+  });
+  diff.aborted_at = walk.err();
+  diff
+}
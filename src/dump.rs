@@ -0,0 +1,155 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Heap Dump
+//!
+//! Write a human readable listing of all memory blocks on the HEAP and the lists of re-usable memory blocks to any
+//! ``core::fmt::Write`` sink, like a UART console. The dump does not require any heap memory allocation itself.
+//!
+//! Only the memory blocks of the bucket allocator could be listed, so dumping the HEAP is not available with the
+//! ``buddy`` or ``tlsf`` feature.
+//!
+
+use crate::memory::{self, MemoryDescriptor, FREE_BUCKETS, MM_FREE_MAGIC, MM_MAGIC};
+use core::fmt::{self, Write};
+use core::sync::atomic::Ordering;
+
+/// Write a listing of the HEAP to the given sink. For each memory block its address, size, bucket, state and payload
+/// address is written, followed by a summary of each non-empty free bucket list.
+///
+/// This gives only a consistent picture if no other core is allocating or freeing memory while dumping the HEAP.
+///
+/// # Example
+/// ```ignore
+/// # use ruspiro_allocator::dump_heap;
+/// // any type implementing core::fmt::Write, like the console of your choice, could be used as sink
+/// let mut out = String::new();
+/// dump_heap(&mut out).unwrap();
+/// ```
+pub fn dump_heap<W: Write>(out: &mut W) -> fmt::Result {
+  let heap_start = memory::heap_bottom();
+  let heap_end = memory::heap_top();
+  writeln!(
+    out,
+    "HEAP {:#010x} - {:#010x} ({} bytes)",
+    heap_start,
+    heap_end,
+    heap_end - heap_start
+  )?;
+  writeln!(
+    out,
+    "  {:<12} {:>12} {:>6}  {:<11} payload",
+    "block", "size", "bucket", "state"
+  )?;
+
+  let mut result = Ok(());
+  let mut free_blocks = 0;
+  let walked = memory::walk_heap(|block, descriptor| {
+    result = dump_block(out, block, descriptor);
+    if descriptor.magic == MM_FREE_MAGIC {
+      free_blocks += 1;
+    }
+    result.is_ok()
+  });
+  result?;
+  if let Err(block) = walked {
+    writeln!(
+      out,
+      "  corrupted memory block at {:#010x}, the HEAP could not be walked any further",
+      block
+    )?;
+  }
+
+  writeln!(out, "FREE BUCKETS")?;
+  writeln!(
+    out,
+    "  {:>6} {:>12} {:>8} {:>12}",
+    "bucket", "size", "blocks", "bytes"
+  )?;
+  // do not follow a list with more entries than freed memory blocks exist, it most likely contains a cycle. If the
+  // HEAP could not be walked completely the number of freed memory blocks is not known, so the size of the HEAP bounds
+  // the list
+  let max_blocks = match walked {
+    Ok(()) => free_blocks,
+    Err(_) => memory::max_heap_blocks(),
+  };
+  for (bucket, queue) in FREE_BUCKETS.iter().enumerate() {
+    let (mut blocks, mut bytes) = (0, 0);
+    let mut block = queue.head.load(Ordering::Acquire);
+    while block != 0 && blocks < max_blocks && memory::within_heap(block) {
+      let descriptor = unsafe { *(block as *const MemoryDescriptor) };
+      blocks += 1;
+      bytes += descriptor.size;
+      block = descriptor.next;
+    }
+    if blocks > 0 {
+      match memory::bucket_size(bucket) {
+        Some(size) => writeln!(
+          out,
+          "  {:>6} {:>#12x} {:>8} {:>#12x}",
+          bucket, size, blocks, bytes
+        )?,
+        None => writeln!(
+          out,
+          "  {:>6} {:>12} {:>8} {:>#12x}",
+          bucket, "dynamic", blocks, bytes
+        )?,
+      }
+    }
+    if block != 0 && blocks >= max_blocks {
+      writeln!(
+        out,
+        "  free bucket {} has more than {} entries at {:#010x}, the list most likely contains a cycle",
+        bucket, max_blocks, block
+      )?;
+    } else if block != 0 {
+      // the descriptor of an entry outside the HEAP is not read, as its address could not be trusted
+      writeln!(
+        out,
+        "  free bucket {} links to {:#010x} outside the HEAP, the list could not be followed any further",
+        bucket, block
+      )?;
+    }
+  }
+
+  Ok(())
+}
+
+/// Write a single line describing the given memory block
+fn dump_block<W: Write>(out: &mut W, block: usize, descriptor: &MemoryDescriptor) -> fmt::Result {
+  let (size, bucket, payload) = (descriptor.size, descriptor.bucket, descriptor.payload_addr);
+  match descriptor.magic {
+    MM_MAGIC => writeln!(
+      out,
+      "  {:#010x}   {:>#12x} {:>6}  {:<11} {:#010x}",
+      block, size, bucket, "live", payload
+    ),
+    MM_FREE_MAGIC => writeln!(
+      out,
+      "  {:#010x}   {:>#12x} {:>6}  {:<11} -",
+      block,
+      size,
+      bucket,
+      free_state(block)
+    ),
+    magic => writeln!(
+      out,
+      "  {:#010x}   {:>#12x} {:>6}  unknown magic {:#x}",
+      block, size, bucket, magic
+    ),
+  }
+}
+
+/// The state of a freed memory block
+fn free_state(_block: usize) -> &'static str {
+  #[cfg(feature = "quarantine")]
+  if crate::quarantine::contains(_block) {
+    return "quarantined";
+  }
+
+  "free"
+}
@@ -0,0 +1,104 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Emergency Pool
+//!
+//! Once the HEAP is exhausted the panic handler and the logging code still need some memory to format their messages.
+//! With the ``emergency-pool`` feature a small pool is reserved up front. While the emergency mode is active the global
+//! allocator serves any allocation the HEAP could not serve from this pool. The emergency mode is activated by the
+//! allocation error handler before it panics and could be activated from the panic handler with
+//! [set_emergency_mode].
+//!
+//! The pool is a simple bump allocator. Memory freed is only given back if it is the last one allocated from the pool,
+//! as it is only meant to print some diagnostics before the system halts. The size of the pool is configured at build
+//! time with the environment variable ``RUSPIRO_ALLOCATOR_EMERGENCY_SIZE``, see the build script for details.
+//!
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// The generated constant is: pub const EMERGENCY_POOL_SIZE: usize = ...;
+include!(concat!(env!("OUT_DIR"), "/emergency.rs"));
+
+/// The size of the header in front of each allocation from the pool containing its size
+const HEADER_SIZE: usize = 16;
+
+/// The memory of the emergency pool
+#[repr(C, align(16))]
+struct Pool(UnsafeCell<[u8; EMERGENCY_POOL_SIZE]>);
+
+unsafe impl Sync for Pool {}
+
+static POOL: Pool = Pool(UnsafeCell::new([0; EMERGENCY_POOL_SIZE]));
+
+/// The offset of the first unused byte of the pool
+static POOL_TOP: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the global allocator shall fall back to the pool
+static EMERGENCY_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Activate or deactivate the emergency mode. While it is active allocations the HEAP could not serve are served from
+/// the emergency pool. This is meant to be called from the panic handler, so it could still print its message once the
+/// HEAP is exhausted.
+///
+/// # Example
+/// ```ignore
+/// #[panic_handler]
+/// fn panic(info: &PanicInfo) -> ! {
+///   ruspiro_allocator::set_emergency_mode(true);
+///   error!("{}", info);
+///   loop {}
+/// }
+/// ```
+pub fn set_emergency_mode(active: bool) {
+  EMERGENCY_MODE.store(active, Ordering::Release);
+}
+
+/// Whether the emergency mode is active
+pub fn emergency_mode() -> bool {
+  EMERGENCY_MODE.load(Ordering::Acquire)
+}
+
+/// Allocate memory of the given size and alignment from the pool. Returns a null pointer if the pool is exhausted
+pub(crate) fn alloc(size: usize, align: usize) -> *mut u8 {
+  let base = POOL.0.get() as usize;
+  let mut top = POOL_TOP.load(Ordering::Acquire);
+  loop {
+    let payload = (base + top + HEADER_SIZE + align - 1) & !(align - 1);
+    let new_top = match (payload - base).checked_add(size) {
+      Some(new_top) if new_top <= EMERGENCY_POOL_SIZE => new_top,
+      _ => return core::ptr::null_mut(),
+    };
+    match POOL_TOP.compare_exchange_weak(top, new_top, Ordering::AcqRel, Ordering::Acquire) {
+      Ok(_) => {
+        unsafe { *((payload - HEADER_SIZE) as *mut usize) = size };
+        return payload as *mut u8;
+      }
+      Err(current) => top = current,
+    }
+  }
+}
+
+/// Free memory allocated from the pool. It is only given back if it is the last one allocated
+pub(crate) fn free(ptr: *mut u8) {
+  let base = POOL.0.get() as usize;
+  let header = ptr as usize - HEADER_SIZE;
+  let end = ptr as usize + usable_size(ptr) - base;
+  let _ = POOL_TOP.compare_exchange(end, header - base, Ordering::AcqRel, Ordering::Relaxed);
+}
+
+/// The number of bytes usable at the given address of memory allocated from the pool
+pub(crate) fn usable_size(ptr: *mut u8) -> usize {
+  unsafe { *((ptr as usize - HEADER_SIZE) as *const usize) }
+}
+
+/// Whether the given address belongs to the pool
+#[inline]
+pub(crate) fn contains(ptr: *mut u8) -> bool {
+  let base = POOL.0.get() as usize;
+  (base..base + EMERGENCY_POOL_SIZE).contains(&(ptr as usize))
+}
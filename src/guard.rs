@@ -0,0 +1,123 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Guard Bytes
+//!
+//! With the ``guard-bytes`` feature active each payload handed out by the allocator is surrounded by two canary
+//! redzones filled with a known pattern. Writing past the end (or in front of the start) of an allocation overwrites
+//! those canaries instead of the ``MemoryDescriptor`` of the following block. The canaries are verified whenever a
+//! memory block is freed and on demand with [check_guards].
+//!
+
+use crate::memory::{self, MemoryDescriptor, GUARD_SIZE, MM_MAGIC};
+use core::fmt;
+
+/// The pattern the redzones are filled with
+const GUARD_PATTERN: u8 = 0xFD;
+
+/// The redzone of a memory block where a corruption has been detected
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GuardZone {
+  /// The guard bytes right in front of the payload
+  Front,
+  /// The guard bytes right after the requested payload size
+  Rear,
+}
+
+/// Description of guard bytes that have been overwritten
+#[derive(Copy, Clone, Debug)]
+pub struct GuardViolation {
+  /// The address of the memory block (its descriptor) the corrupted guard bytes belong to
+  pub block: usize,
+  /// The bucket index of this memory block
+  pub bucket: usize,
+  /// The payload address handed out for this memory block
+  pub payload: usize,
+  /// The redzone that has been overwritten
+  pub zone: GuardZone,
+  /// The offset of the first overwritten byte within the redzone
+  pub offset: usize,
+  /// The actual content of the whole redzone
+  pub bytes: [u8; GUARD_SIZE],
+}
+
+impl fmt::Display for GuardViolation {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{:?} guard bytes of memory block {:#x} (bucket {}, payload {:#x}) overwritten at offset {}: {:02x?}",
+      self.zone, self.block, self.bucket, self.payload, self.offset, self.bytes
+    )
+  }
+}
+
+/// Check the guard bytes of all live memory blocks on the HEAP. Each detected corruption is passed to the given
+/// function. Returns the number of corrupted redzones found.
+///
+/// This gives only a consistent picture if no other core is allocating or freeing memory while checking the HEAP.
+///
+/// # Example
+/// ```ignore
+/// # use ruspiro_allocator::check_guards;
+/// let corrupted = check_guards(|violation| {
+///   // print the violation to the console of your choice
+///   let _ = violation;
+/// });
+/// assert_eq!(corrupted, 0);
+/// ```
+pub fn check_guards<F>(mut f: F) -> usize
+where
+  F: FnMut(&GuardViolation),
+{
+  let mut count = 0;
+  let _ = memory::walk_heap(|block, descriptor| {
+    if descriptor.magic == MM_MAGIC {
+      if let Some(violation) = check(block, descriptor) {
+        count += 1;
+        f(&violation);
+      }
+    }
+    true
+  });
+
+  count
+}
+
+/// Fill the redzones around the payload of the given memory block with the guard pattern
+pub(crate) fn arm(descriptor: &MemoryDescriptor) {
+  let payload = descriptor.payload_addr;
+  let req_size = descriptor.req_size;
+  unsafe {
+    core::ptr::write_bytes((payload - GUARD_SIZE) as *mut u8, GUARD_PATTERN, GUARD_SIZE);
+    core::ptr::write_bytes((payload + req_size) as *mut u8, GUARD_PATTERN, GUARD_SIZE);
+  }
+}
+
+/// Verify the redzones around the payload of the given memory block. The first corrupted one is returned.
+pub(crate) fn check(block: usize, descriptor: &MemoryDescriptor) -> Option<GuardViolation> {
+  let payload = descriptor.payload_addr;
+  let req_size = descriptor.req_size;
+  [
+    (GuardZone::Front, payload - GUARD_SIZE),
+    (GuardZone::Rear, payload + req_size),
+  ]
+  .iter()
+  .find_map(|&(zone, zone_addr)| {
+    let bytes = unsafe { *(zone_addr as *const [u8; GUARD_SIZE]) };
+    bytes
+      .iter()
+      .position(|&byte| byte != GUARD_PATTERN)
+      .map(|offset| GuardViolation {
+        block,
+        bucket: descriptor.bucket,
+        payload,
+        zone,
+        offset,
+        bytes,
+      })
+  })
+}
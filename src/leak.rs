@@ -0,0 +1,281 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Leak Tracking
+//!
+//! With the ``leak-tracking`` feature each live allocation is recorded in a lock free side table together with its
+//! requested size and a tag. The tag is a subsystem ID set for the current core with [leak_tag] as long as the
+//! returned guard lives. [leak_report] groups the live allocations by their tag, so a subsystem whose allocations keep
+//! growing on a long running board reveals itself.
+//!
+//! The table is an open addressing hash table with [LEAK_TABLE_ENTRIES] slots. Slots are never emptied but marked as
+//! removed, so the lookup of an address never stops early while other cores insert or remove entries. As removed
+//! slots pile up on a long running board, an address is only looked up in the [LEAK_PROBE_LENGTH] slots following its
+//! hash. Allocations that do not find a free slot there are only counted.
+//!
+
+use crate::cpu;
+use core::fmt;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+/// The number of bits of the index into the side table
+const LEAK_TABLE_BITS: u32 = 12;
+
+/// The number of live allocations the side table is able to track
+pub const LEAK_TABLE_ENTRIES: usize = 1 << LEAK_TABLE_BITS;
+
+/// The number of slots probed for an address. This bounds the cost of each lookup, even for the free of an untracked
+/// allocation once all slots have been used
+pub const LEAK_PROBE_LENGTH: usize = 64;
+
+/// The maximum number of tags listed in a [LeakReport]. The allocations of any further tag are only summed up
+pub const MAX_LEAK_GROUPS: usize = 32;
+
+/// The tag of the allocations done while no other tag is set
+pub const UNTAGGED: LeakTag = 0;
+
+/// The ID of the subsystem owning an allocation
+pub type LeakTag = u32;
+
+/// Address of a slot that has never been used
+const EMPTY: usize = 0;
+
+/// Address of a slot whose allocation has been freed. Payload addresses are aligned, so this is never a valid one
+const REMOVED: usize = 1;
+
+/// An entry of the side table
+struct Slot {
+  address: AtomicUsize,
+  size: AtomicUsize,
+  tag: AtomicU32,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNUSED_SLOT: Slot = Slot {
+  address: AtomicUsize::new(EMPTY),
+  size: AtomicUsize::new(0),
+  tag: AtomicU32::new(UNTAGGED),
+};
+
+static TABLE: [Slot; LEAK_TABLE_ENTRIES] = [UNUSED_SLOT; LEAK_TABLE_ENTRIES];
+
+/// The number of allocations not tracked as no slot near their address has been free
+static UNTRACKED: AtomicUsize = AtomicUsize::new(0);
+
+/// The tag currently set on each core
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_TAG: AtomicU32 = AtomicU32::new(UNTAGGED);
+static CURRENT_TAG: [AtomicU32; cpu::CORE_COUNT] = [NO_TAG; cpu::CORE_COUNT];
+
+/// Guard setting the tag of the allocations done on the current core. The previous tag is restored once it is
+/// dropped
+pub struct LeakTagGuard {
+  core: usize,
+  previous: LeakTag,
+}
+
+impl Drop for LeakTagGuard {
+  fn drop(&mut self) {
+    CURRENT_TAG[self.core].store(self.previous, Ordering::Relaxed);
+  }
+}
+
+/// Tag all allocations done on the current core with the given subsystem ID as long as the returned guard lives.
+/// The guard need to be dropped on the same core it has been created on.
+///
+/// # Example
+/// ```ignore
+/// const NETWORK: LeakTag = 3;
+///
+/// let _tag = ruspiro_allocator::leak_tag(NETWORK);
+/// let buffer: Vec<u8> = Vec::with_capacity(1500);
+/// ```
+pub fn leak_tag(tag: LeakTag) -> LeakTagGuard {
+  let core = cpu::core_id();
+  LeakTagGuard {
+    core,
+    previous: CURRENT_TAG[core].swap(tag, Ordering::Relaxed),
+  }
+}
+
+/// The live allocations of one tag
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LeakGroup {
+  /// The tag of the allocations
+  pub tag: LeakTag,
+  /// The number of live allocations
+  pub allocations: usize,
+  /// The sum of their requested sizes
+  pub bytes: usize,
+}
+
+/// The live allocations grouped by their tag
+#[derive(Copy, Clone, Debug)]
+pub struct LeakReport {
+  /// The number of tags listed in this report
+  pub group_count: usize,
+  /// The allocations of the tags not fitting into this report any more summed up, its tag is meaningless
+  pub ungrouped: LeakGroup,
+  /// The number of allocations that could not be tracked as no slot near their address has been free, including freed
+  /// ones
+  pub untracked: usize,
+  groups: [LeakGroup; MAX_LEAK_GROUPS],
+}
+
+impl LeakReport {
+  /// Iterate over the tags listed in this report ordered by the bytes they occupy, the largest first
+  pub fn groups(&self) -> impl Iterator<Item = &LeakGroup> {
+    self.groups[..self.group_count].iter()
+  }
+
+  fn add(&mut self, tag: LeakTag, size: usize) {
+    let listed = self.group_count;
+    let group = match self.groups[..listed]
+      .iter()
+      .position(|group| group.tag == tag)
+    {
+      Some(index) => &mut self.groups[index],
+      None if listed < MAX_LEAK_GROUPS => {
+        self.group_count += 1;
+        self.groups[listed].tag = tag;
+        &mut self.groups[listed]
+      }
+      None => &mut self.ungrouped,
+    };
+    group.allocations += 1;
+    group.bytes += size;
+  }
+}
+
+impl fmt::Display for LeakReport {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "{:>10} {:>12} {:>12}", "tag", "allocations", "bytes")?;
+    for group in self.groups() {
+      writeln!(
+        f,
+        "{:>10} {:>12} {:>12}",
+        group.tag, group.allocations, group.bytes
+      )?;
+    }
+    if self.ungrouped.allocations > 0 {
+      writeln!(
+        f,
+        "{:>10} {:>12} {:>12}",
+        "other", self.ungrouped.allocations, self.ungrouped.bytes
+      )?;
+    }
+    if self.untracked > 0 {
+      writeln!(f, "{} allocations not tracked", self.untracked)?;
+    }
+    Ok(())
+  }
+}
+
+/// Group the live allocations by their tag. Allocations done without any tag set are listed with [UNTAGGED].
+///
+/// This gives only a consistent picture if no other core is allocating or freeing memory while the report is
+/// created.
+///
+/// # Example
+/// ```ignore
+/// let report = ruspiro_allocator::leak_report();
+/// info!("{}", report);
+/// ```
+pub fn leak_report() -> LeakReport {
+  let mut report = LeakReport {
+    group_count: 0,
+    ungrouped: LeakGroup::default(),
+    untracked: UNTRACKED.load(Ordering::Relaxed),
+    groups: [LeakGroup::default(); MAX_LEAK_GROUPS],
+  };
+  tracked_allocations(|_, size, tag| report.add(tag, size));
+  report.groups[..report.group_count].sort_unstable_by_key(|group| core::cmp::Reverse(group.bytes));
+  report
+}
+
+/// Call the given function with the address, the requested size and the tag of each live allocation tracked
+///
+/// # Example
+/// ```ignore
+/// ruspiro_allocator::tracked_allocations(|address, size, tag| {
+///   if tag == NETWORK {
+///     info!("{:#x}: {} Bytes", address, size);
+///   }
+/// });
+/// ```
+pub fn tracked_allocations<F: FnMut(usize, usize, LeakTag)>(mut f: F) {
+  for slot in TABLE.iter() {
+    let address = slot.address.load(Ordering::Acquire);
+    if address != EMPTY && address != REMOVED {
+      f(
+        address,
+        slot.size.load(Ordering::Relaxed),
+        slot.tag.load(Ordering::Relaxed),
+      );
+    }
+  }
+}
+
+/// The slots to probe for the given address
+fn probe(address: usize) -> impl Iterator<Item = &'static Slot> {
+  // Fibonacci hashing spreads the aligned addresses over the whole table, the upper bits of the product are the best
+  // mixed ones
+  let start =
+    address.wrapping_mul(0x9E37_79B9_7F4A_7C15_u64 as usize) >> (usize::BITS - LEAK_TABLE_BITS);
+  (0..LEAK_PROBE_LENGTH).map(move |step| &TABLE[(start + step) % LEAK_TABLE_ENTRIES])
+}
+
+/// Record the allocation at the given address with the tag set on the current core
+pub(crate) fn track(address: *mut u8, size: usize) {
+  let tag = CURRENT_TAG[cpu::core_id()].load(Ordering::Relaxed);
+  insert(address as usize, size, tag);
+}
+
+/// Remove the allocation at the given address from the table. Returns the tag it has been recorded with or the tag
+/// set on the current core if it has not been tracked
+pub(crate) fn untrack(address: *mut u8) -> LeakTag {
+  remove(address as usize).unwrap_or_else(|| CURRENT_TAG[cpu::core_id()].load(Ordering::Relaxed))
+}
+
+/// Record the allocation at the given address again with the tag it has been untracked with, for example after it has
+/// been re-allocated
+pub(crate) fn retrack(address: *mut u8, size: usize, tag: LeakTag) {
+  insert(address as usize, size, tag);
+}
+
+fn insert(address: usize, size: usize, tag: LeakTag) {
+  for slot in probe(address) {
+    let current = slot.address.load(Ordering::Relaxed);
+    if (current == EMPTY || current == REMOVED)
+      && slot
+        .address
+        .compare_exchange(current, address, Ordering::AcqRel, Ordering::Relaxed)
+        .is_ok()
+    {
+      slot.size.store(size, Ordering::Relaxed);
+      slot.tag.store(tag, Ordering::Relaxed);
+      return;
+    }
+  }
+  UNTRACKED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Remove the given address and return the tag it has been recorded with
+fn remove(address: usize) -> Option<LeakTag> {
+  for slot in probe(address) {
+    match slot.address.load(Ordering::Acquire) {
+      EMPTY => break,
+      current if current == address => {
+        let tag = slot.tag.load(Ordering::Relaxed);
+        slot.address.store(REMOVED, Ordering::Release);
+        return Some(tag);
+      }
+      _ => (),
+    }
+  }
+  None
+}
@@ -5,13 +5,18 @@
  * License: Apache License 2.0 / MIT
  **********************************************************************************************************************/
 #![doc(html_root_url = "https://docs.rs/ruspiro-allocator/||VERSION||")]
-#![cfg_attr(not(any(test, doctest)), no_std)]
-#![feature(alloc_error_handler)]
+#![cfg_attr(not(any(test, doctest, feature = "std")), no_std)]
+#![cfg_attr(not(any(test, doctest, feature = "std")), feature(alloc_error_handler))]
+#![cfg_attr(any(test, feature = "std"), allow(dead_code))]
+#![cfg_attr(feature = "allocator-api", feature(allocator_api))]
 //! # Custom Allocator for HEAP memory allocations
 //!
 //! This crate provides a custom allocator for heap memory. If any baremetal crate uses functions and structures from
-//! the ``alloc`` crate an allocator need to be provided as well. However, this crate does not export any public
-//! API to be used. It only encapsulates the memeory allocator that shall be linked into the binary.
+//! the ``alloc`` crate an allocator need to be provided as well. This crate encapsulates the memeory allocator that
+//! shall be linked into the binary. Beside this it only exports some diagnostic functions like [verify_heap],
+//! [dump_heap], [diff] or [write_snapshot] that help to find memory related issues and [heap_stats] reporting the HEAP usage
+//! of the allocator backend chosen by cargo feature. Allocations that shall not fail silently could be done with
+//! [try_alloc] reporting the reason of a failure as [AllocError].
 //!
 //! # Prerequisit
 //!
@@ -39,41 +44,298 @@
 //! }
 //! ```
 //!
+//! # Features
+//!
+//! Feature        | Description
+//! ---------------|------------------------------------------------------------------------------------------------
+//! ``guard-bytes``| Surround each payload with canary redzones that are verified when the memory is freed and on demand with ``check_guards``. Corruptions are reported with the block address, its bucket and the overwritten bytes.
+//! ``poison``     | Fill new allocations with ``POISON_FRESH`` and freed memory with ``POISON_FREED``. The freed pattern is verified once a memory block is re-used to reveal writes after free.
+//! ``quarantine`` | Delay the re-usage of freed memory blocks with a bounded FIFO. The freed pattern is verified again once a memory block leaves the quarantine. ``flush_quarantine`` releases all memory blocks waiting in the quarantine. Implies ``poison``.
+//! ``trace``      | Record each ``alloc``, ``free`` and ``alloc_page`` in a lock free ring buffer that could be drained with ``drain_trace`` or exported with ``write_trace``. The timestamp of each event is taken from the clock set with ``set_trace_clock``.
+//! ``buddy``      | Manage the HEAP as binary buddy system instead of buckets. Each memory block has a power-of-two size, is aligned to its size and is merged with its buddy once both are free. The diagnostic functions and debugging features cover the bucket allocator only.
+//! ``tlsf``       | Manage the HEAP with a Two-Level Segregated Fit allocator offering allocation and free in constant time with immediate merging of free memory blocks. A [Heap] of this kind could also be set up on any memory region. The diagnostic functions and debugging features cover the bucket allocator only. Preferred over ``buddy`` if both are enabled.
+//! ``allocator-api``| Implement the unstable ``Allocator`` trait for [RusPiRoAllocator], so collections created with it make use of the whole usable size of each memory block.
+//! ``c-abi``      | Export ``malloc``, ``free``, ``calloc``, ``realloc``, ``aligned_alloc`` and ``posix_memalign`` for C code linked into the binary. The build script generates the header ``ruspiro_allocator.h`` declaring them, its directory is passed to dependent build scripts as ``DEP_RUSPIRO_ALLOCATOR_INCLUDE``.
+//! ``sbrk``       | Export ``_sbrk`` for the ``malloc`` of newlib. It hands out memory of a region reserved from the allocator, so the heaps of Rust and C code do not collide. The size of the region is configured at build time with ``RUSPIRO_ALLOCATOR_SBRK_SIZE``, 1 MB by default.
+//! ``memory-pressure``| Call the callbacks registered with [register_pressure_callback] in the order of their priority to release memory before an allocation fails because the HEAP is exhausted. The allocation is retried after each callback that released memory.
+//! ``watermarks``  | Call the callback set with [set_watermarks] once the HEAP size or the bytes in use cross any of the thresholds given in percent of the HEAP region, in either direction. [high_water_mark] reports the highest value seen.
+//! ``leak-tracking``| Record each live allocation with its size and the subsystem tag set with [leak_tag] in a lock free side table. [leak_report] groups the live allocations by their tag.
+//! ``measure``    | Count the allocations, frees, bytes allocated and freed and the peak usage of a closure run with [measure]. The counters are kept per core, so work on other cores does not pollute the result.
+//! ``no-alloc``   | Call the hook set with [set_no_alloc_hook] for any allocation, re-allocation or free through [RusPiRoAllocator] on a core marked with [no_alloc] or [NoAllocGuard]. Without a hook the violation panics.
+//! ``emergency-pool``| Reserve a small pool serving the allocations the exhausted HEAP could not serve while the emergency mode is active. The allocation error handler activates it before it panics, the panic handler could activate it with ``set_emergency_mode``. The size of the pool is configured at build time with ``RUSPIRO_ALLOCATOR_EMERGENCY_SIZE``, 4 KB by default.
+//! ``std``        | Build the crate for the host machine to run the ``heap-analyzer`` on heap snapshots or the ``trace-replay`` on allocation traces. The custom allocator is not registered as global allocator in this case but could be used on a simulated heap.
+//!
 
 // this is crate is required to bring the core memory functions like memset, memcpy etc. into the link process
 #[doc(hidden)]
 extern crate rlibc;
 
 /// this specifies the custom memory allocator to use whenever heap memory need to be allocated or freed
-#[cfg_attr(not(any(test, doctest)), global_allocator)]
+#[cfg_attr(not(any(test, doctest, feature = "std")), global_allocator)]
 static ALLOCATOR: RusPiRoAllocator = RusPiRoAllocator;
 
 use core::alloc::{GlobalAlloc, Layout};
+#[cfg(feature = "allocator-api")]
+use core::ptr::NonNull;
 
+// with another backend chosen only the HEAP bounds of the bucket allocator are used
+#[cfg_attr(any(feature = "buddy", feature = "tlsf"), allow(dead_code))]
 mod memory;
 
-struct RusPiRoAllocator;
+#[cfg(feature = "buddy")]
+#[cfg_attr(feature = "tlsf", allow(dead_code))]
+mod buddy;
+
+#[cfg(feature = "tlsf")]
+mod tlsf;
+#[cfg(feature = "tlsf")]
+pub use tlsf::Heap;
+
+#[cfg(any(
+  feature = "buddy",
+  feature = "tlsf",
+  feature = "memory-pressure",
+  feature = "watermarks"
+))]
+mod lock;
+
+mod backend;
+pub use backend::{heap_stats, try_alloc, usable_size, AllocError, HeapStats};
+use backend::{Backend, BACKEND};
+
+#[cfg(feature = "c-abi")]
+mod cabi;
+
+#[cfg(feature = "sbrk")]
+mod sbrk;
+
+#[cfg(feature = "emergency-pool")]
+mod emergency;
+#[cfg(feature = "emergency-pool")]
+pub use emergency::{emergency_mode, set_emergency_mode, EMERGENCY_POOL_SIZE};
+
+#[cfg(feature = "memory-pressure")]
+mod pressure;
+#[cfg(feature = "memory-pressure")]
+pub use pressure::{
+  register_pressure_callback, unregister_pressure_callback, PressureCallback, PressureHandle,
+  MAX_PRESSURE_CALLBACKS,
+};
+
+#[cfg(feature = "watermarks")]
+mod watermark;
+#[cfg(feature = "watermarks")]
+pub use watermark::{
+  clear_watermarks, high_water_mark, set_watermarks, WatermarkCallback, WatermarkError,
+  WatermarkEvent, WatermarkMetric, MAX_WATERMARKS,
+};
+
+#[cfg(feature = "leak-tracking")]
+mod leak;
+#[cfg(feature = "leak-tracking")]
+pub use leak::{
+  leak_report, leak_tag, tracked_allocations, LeakGroup, LeakReport, LeakTag, LeakTagGuard,
+  LEAK_PROBE_LENGTH, LEAK_TABLE_ENTRIES, MAX_LEAK_GROUPS, UNTAGGED,
+};
+
+#[cfg(feature = "measure")]
+mod measure;
+#[cfg(feature = "measure")]
+pub use measure::{measure, Measurement};
+
+#[cfg(feature = "no-alloc")]
+mod no_alloc;
+#[cfg(feature = "no-alloc")]
+pub use no_alloc::{no_alloc, set_no_alloc_hook, NoAllocGuard, NoAllocHook, NoAllocOp};
+
+mod cpu;
+
+#[cfg(not(any(feature = "buddy", feature = "tlsf")))]
+mod verify;
+#[cfg(not(any(feature = "buddy", feature = "tlsf")))]
+pub use verify::{verify_heap, HeapReport, HeapViolation, MAX_VIOLATIONS};
+
+#[cfg(not(any(feature = "buddy", feature = "tlsf")))]
+mod dump;
+#[cfg(not(any(feature = "buddy", feature = "tlsf")))]
+pub use dump::dump_heap;
+
+#[cfg(not(any(feature = "buddy", feature = "tlsf")))]
+mod diff;
+#[cfg(not(any(feature = "buddy", feature = "tlsf")))]
+pub use diff::{diff, mark, DiffBlock, HeapDiff, HeapMark, MAX_DIFF_BLOCKS};
+
+mod snapshot;
+#[cfg(not(any(feature = "buddy", feature = "tlsf")))]
+pub use snapshot::write_snapshot;
+pub use snapshot::{
+  SnapshotError, SnapshotReader, SnapshotRecord, SnapshotRecords, SNAPSHOT_MAGIC, SNAPSHOT_VERSION,
+};
+
+#[cfg(feature = "std")]
+pub mod simulation;
+
+#[cfg(feature = "guard-bytes")]
+mod guard;
+#[cfg(feature = "guard-bytes")]
+pub use guard::{check_guards, GuardViolation, GuardZone};
+
+#[cfg(feature = "poison")]
+mod poison;
+#[cfg(feature = "poison")]
+pub use poison::{PoisonViolation, POISON_FREED, POISON_FRESH};
+
+#[cfg(feature = "quarantine")]
+mod quarantine;
+#[cfg(feature = "quarantine")]
+pub use quarantine::flush_quarantine;
+
+#[cfg(feature = "trace")]
+mod trace;
+#[cfg(feature = "trace")]
+pub use trace::{
+  drain_trace, set_trace_clock, write_trace, TraceDrain, TraceError, TraceEvent, TraceOp,
+  TraceReader, TRACE_ENTRIES, TRACE_MAGIC, TRACE_VERSION,
+};
+
+// the bucket configuration parser of the build script is tested on the host along with the library
+#[cfg(test)]
+#[path = "../build.rs"]
+mod build_script;
+
+/// The custom allocator registered as global allocator. With the ``allocator-api`` feature it could also be handed to
+/// the collections explicitly, which then make use of the whole usable size of each memory block
+///
+/// # Example
+/// ```ignore
+/// let mut v: Vec<u32, _> = Vec::with_capacity_in(10, ruspiro_allocator::RusPiRoAllocator);
+/// ```
+pub struct RusPiRoAllocator;
 
 unsafe impl GlobalAlloc for RusPiRoAllocator {
   #[inline]
   unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-    memory::alloc(layout.size(), layout.align())
+    backend::abort_on_unwind(|| {
+      #[cfg(feature = "no-alloc")]
+      no_alloc::check(NoAllocOp::Alloc, layout);
+      self.alloc_memory(layout)
+    })
   }
 
   #[inline]
-  unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-    memory::free(ptr)
+  #[cfg_attr(not(feature = "no-alloc"), allow(unused_variables))]
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    backend::abort_on_unwind(|| {
+      #[cfg(feature = "no-alloc")]
+      no_alloc::check(NoAllocOp::Dealloc, layout);
+      self.dealloc_memory(ptr)
+    })
   }
 
   #[inline]
   unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
-    let ptr = memory::alloc(layout.size(), layout.align());
-    memset(ptr, 0x0, layout.size());
+    let ptr = self.alloc(layout);
+    if !ptr.is_null() {
+      memset(ptr, 0x0, layout.size());
+    }
+    ptr
+  }
+
+  #[inline]
+  unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    backend::abort_on_unwind(|| {
+      #[cfg(feature = "no-alloc")]
+      no_alloc::check(NoAllocOp::Realloc, layout);
+      // memory of the emergency pool is always moved, either to the HEAP or within the pool
+      #[cfg(feature = "emergency-pool")]
+      if emergency::contains(ptr) {
+        return self.relocate(ptr, layout, new_size);
+      }
+      // the memory is accounted with its whole usable size, see [RusPiRoAllocator::alloc_memory]
+      let size = BACKEND.usable_size(ptr);
+      let reallocation = backend::reallocating(ptr);
+      let new_ptr = BACKEND.realloc(ptr, layout, new_size);
+      let usable_size = if new_ptr.is_null() {
+        0
+      } else {
+        BACKEND.usable_size(new_ptr)
+      };
+      backend::reallocated(reallocation, ptr, size, new_ptr, usable_size);
+      #[cfg(feature = "emergency-pool")]
+      if new_ptr.is_null() && emergency::emergency_mode() {
+        return self.relocate(ptr, layout, new_size);
+      }
+      new_ptr
+    })
+  }
+}
+
+impl RusPiRoAllocator {
+  /// Allocate memory for the given layout. Each public operation checks for a no-allocation region only once, so this
+  /// is used by all of them
+  #[inline]
+  unsafe fn alloc_memory(&self, layout: Layout) -> *mut u8 {
+    let ptr = BACKEND.alloc(layout.size(), layout.align());
+    // the memory is accounted with its whole usable size like the memory of [try_alloc], as memory of either could be
+    // freed here
+    let size = if ptr.is_null() {
+      0
+    } else {
+      BACKEND.usable_size(ptr)
+    };
+    backend::allocated(ptr, size);
+    // once the HEAP is exhausted the emergency pool serves the allocations while the emergency mode is active
+    #[cfg(feature = "emergency-pool")]
+    if ptr.is_null() && emergency::emergency_mode() {
+      return emergency::alloc(layout.size(), layout.align());
+    }
     ptr
   }
+
+  /// Free the memory at the given address, see [RusPiRoAllocator::alloc_memory]
+  #[inline]
+  unsafe fn dealloc_memory(&self, ptr: *mut u8) {
+    #[cfg(feature = "emergency-pool")]
+    if emergency::contains(ptr) {
+      return emergency::free(ptr);
+    }
+    let size = BACKEND.usable_size(ptr);
+    backend::freeing(ptr);
+    BACKEND.free(ptr);
+    backend::freed(size);
+  }
+
+  /// Move the memory to a new memory block of the given size
+  #[cfg(feature = "emergency-pool")]
+  unsafe fn relocate(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    let new_ptr = self.alloc_memory(Layout::from_size_align_unchecked(new_size, layout.align()));
+    if !new_ptr.is_null() {
+      core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+      self.dealloc_memory(ptr);
+    }
+    new_ptr
+  }
+}
+
+#[cfg(feature = "allocator-api")]
+unsafe impl core::alloc::Allocator for RusPiRoAllocator {
+  #[inline]
+  fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+    #[cfg(feature = "no-alloc")]
+    no_alloc::check(NoAllocOp::Alloc, layout);
+    try_alloc(layout).map_err(|_| core::alloc::AllocError)
+  }
+
+  #[inline]
+  #[cfg_attr(not(feature = "no-alloc"), allow(unused_variables))]
+  unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+    #[cfg(feature = "no-alloc")]
+    no_alloc::check(NoAllocOp::Dealloc, layout);
+    self.dealloc_memory(ptr.as_ptr())
+  }
 }
 
-#[cfg(not(any(test, doctest)))]
+#[cfg(not(any(test, doctest, feature = "std", feature = "emergency-pool")))]
 #[alloc_error_handler]
 #[allow(clippy::empty_loop)]
 fn alloc_error_handler(_: Layout) -> ! {
@@ -81,6 +343,18 @@ fn alloc_error_handler(_: Layout) -> ! {
   loop {}
 }
 
+/// With the emergency pool available the panic handler is able to report the failed allocation
+#[cfg(all(not(any(test, doctest, feature = "std")), feature = "emergency-pool"))]
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+  emergency::set_emergency_mode(true);
+  panic!(
+    "memory allocation of {} bytes aligned to {} failed",
+    layout.size(),
+    layout.align()
+  );
+}
+
 extern "C" {
   // reference to the compiler built-in function
   fn memset(ptr: *mut u8, value: i32, size: usize) -> *mut u8;
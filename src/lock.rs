@@ -0,0 +1,83 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Spin Lock
+//!
+//! Minimal spin lock guarding the state of the allocator backends that could not be maintained lock free. As the lock
+//! is taken while allocating, code holding it shall never be interrupted by code that allocates on the same core.
+//!
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Data that could only be accessed while holding the lock
+pub(crate) struct SpinLock<T> {
+  locked: AtomicBool,
+  data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+  pub(crate) const fn new(data: T) -> Self {
+    Self {
+      locked: AtomicBool::new(false),
+      data: UnsafeCell::new(data),
+    }
+  }
+
+  /// Spin until the lock could be taken. The lock is released once the returned guard is dropped
+  pub(crate) fn lock(&self) -> SpinLockGuard<'_, T> {
+    while self
+      .locked
+      .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+      .is_err()
+    {
+      while self.locked.load(Ordering::Relaxed) {
+        core::hint::spin_loop();
+      }
+    }
+    SpinLockGuard { lock: self }
+  }
+
+  /// Take the lock if it is free, without spinning. This is safe to use from code that might interrupt the holder of
+  /// the lock on the same core
+  #[cfg(feature = "watermarks")]
+  pub(crate) fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+    self
+      .locked
+      .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+      .ok()
+      .map(|_| SpinLockGuard { lock: self })
+  }
+}
+
+/// Access to the data of a [SpinLock] while holding it
+pub(crate) struct SpinLockGuard<'a, T> {
+  lock: &'a SpinLock<T>,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    unsafe { &*self.lock.data.get() }
+  }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    unsafe { &mut *self.lock.data.get() }
+  }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+  fn drop(&mut self) {
+    self.lock.locked.store(false, Ordering::Release);
+  }
+}
@@ -0,0 +1,134 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Scoped Allocation Accounting
+//!
+//! With the ``measure`` feature the allocations done by a closure could be measured with [measure]. The counters are
+//! kept per core and only while a measurement is running on it, so work on other cores does not pollute the result
+//! and the allocator does not pay for the accounting otherwise. Measurements could be nested.
+//!
+//! Memory is accounted with its whole usable size, as memory of [crate::try_alloc], the ``Allocator`` trait of the
+//! ``allocator-api`` feature or the functions of the ``c-abi`` feature owns all of it and could be freed through the
+//! global allocator, for example as ``Box``. The same size is accounted once the memory is freed.
+//!
+
+use crate::cpu;
+use core::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+
+/// The allocations done within a measured closure
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Measurement {
+  /// The number of allocations, re-allocations count as allocation and free
+  pub allocations: usize,
+  /// The number of memory blocks freed
+  pub frees: usize,
+  /// The sum of the sizes allocated
+  pub bytes_allocated: usize,
+  /// The sum of the sizes freed
+  pub bytes_freed: usize,
+  /// The largest growth of the memory in use compared to the start of the closure. Freeing memory allocated before
+  /// the closure reduces the memory in use as well
+  pub peak: usize,
+}
+
+/// The counters of a single core
+struct Counters {
+  /// The number of measurements running on this core
+  depth: AtomicUsize,
+  allocations: AtomicUsize,
+  frees: AtomicUsize,
+  bytes_allocated: AtomicUsize,
+  bytes_freed: AtomicUsize,
+  /// The memory allocated but not freed yet since the outermost measurement started
+  current: AtomicIsize,
+  /// The largest value of ``current`` since the innermost measurement started
+  peak: AtomicIsize,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: Counters = Counters {
+  depth: AtomicUsize::new(0),
+  allocations: AtomicUsize::new(0),
+  frees: AtomicUsize::new(0),
+  bytes_allocated: AtomicUsize::new(0),
+  bytes_freed: AtomicUsize::new(0),
+  current: AtomicIsize::new(0),
+  peak: AtomicIsize::new(0),
+};
+
+static COUNTERS: [Counters; cpu::CORE_COUNT] = [ZERO; cpu::CORE_COUNT];
+
+/// Run the given closure and measure the allocations done by it on the current core. Allocations of other cores
+/// running at the same time are not accounted.
+///
+/// # Example
+/// ```ignore
+/// let (_, measurement) = ruspiro_allocator::measure(|| handle_frame());
+/// info!(
+///   "{} allocations with {} Bytes, peak {} Bytes",
+///   measurement.allocations, measurement.bytes_allocated, measurement.peak
+/// );
+/// ```
+pub fn measure<R, F: FnOnce() -> R>(f: F) -> (R, Measurement) {
+  let counters = &COUNTERS[cpu::core_id()];
+  let allocations = counters.allocations.load(Ordering::Relaxed);
+  let frees = counters.frees.load(Ordering::Relaxed);
+  let bytes_allocated = counters.bytes_allocated.load(Ordering::Relaxed);
+  let bytes_freed = counters.bytes_freed.load(Ordering::Relaxed);
+  let current = counters.current.load(Ordering::Relaxed);
+  // the peak restarts for this measurement, the one of an enclosing measurement is restored afterwards
+  let outer_peak = counters.peak.swap(current, Ordering::Relaxed);
+
+  counters.depth.fetch_add(1, Ordering::Relaxed);
+  let result = f();
+  counters.depth.fetch_sub(1, Ordering::Relaxed);
+
+  let peak = counters.peak.fetch_max(outer_peak, Ordering::Relaxed);
+  let measurement = Measurement {
+    allocations: counters
+      .allocations
+      .load(Ordering::Relaxed)
+      .wrapping_sub(allocations),
+    frees: counters.frees.load(Ordering::Relaxed).wrapping_sub(frees),
+    bytes_allocated: counters
+      .bytes_allocated
+      .load(Ordering::Relaxed)
+      .wrapping_sub(bytes_allocated),
+    bytes_freed: counters
+      .bytes_freed
+      .load(Ordering::Relaxed)
+      .wrapping_sub(bytes_freed),
+    peak: peak.wrapping_sub(current).max(0) as usize,
+  };
+  (result, measurement)
+}
+
+/// Account for an allocation of the given size on the current core if a measurement is running
+pub(crate) fn allocated(size: usize) {
+  let counters = &COUNTERS[cpu::core_id()];
+  if counters.depth.load(Ordering::Relaxed) == 0 {
+    return;
+  }
+  counters.allocations.fetch_add(1, Ordering::Relaxed);
+  counters.bytes_allocated.fetch_add(size, Ordering::Relaxed);
+  let current = counters
+    .current
+    .fetch_add(size as isize, Ordering::Relaxed)
+    .wrapping_add(size as isize);
+  counters.peak.fetch_max(current, Ordering::Relaxed);
+}
+
+/// Account for memory of the given size freed on the current core if a measurement is running
+pub(crate) fn freed(size: usize) {
+  let counters = &COUNTERS[cpu::core_id()];
+  if counters.depth.load(Ordering::Relaxed) == 0 {
+    return;
+  }
+  counters.frees.fetch_add(1, Ordering::Relaxed);
+  counters.bytes_freed.fetch_add(size, Ordering::Relaxed);
+  counters.current.fetch_sub(size as isize, Ordering::Relaxed);
+}
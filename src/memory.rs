@@ -8,56 +8,42 @@
 //! # Lock Free Memory Management
 //!
 
-use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::backend::{AllocError, Backend, HeapStats};
+use core::alloc::Layout;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
 //use ruspiro_console::*;
 
 /// The magic identifier for a managed memory block
-const MM_MAGIC: u32 = 0xDEAD_BEEF;
-
-/// Memory allocations happens in predefined chunk sizes. This might lead to memory wast in some cases
-/// but this could help increasing the speed for re-usage of freed memory regions as we know which
-/// bucket to look for when re-using. Memory requirements above 1MB are handled individually w/o any
-/// bucket assignment
-#[repr(u32)]
-#[derive(Copy, Clone, Debug)]
-enum MemBucketSize {
-  _64B = 0x00_0040,
-  _128B = 0x00_0080,
-  _256B = 0x00_0100,
-  _512B = 0x00_0200,
-  _1KB = 0x00_0400,
-  _2KB = 0x00_0800,
-  _4KB = 0x00_1000,
-  _8KB = 0x00_2000,
-  _16KB = 0x00_4000,
-  _32KB = 0x00_8000,
-  _64KB = 0x01_0000,
-  _128KB = 0x02_0000,
-  _256KB = 0x04_0000,
-  _512KB = 0x08_0000,
-  _1MB = 0x10_0000,
-  _2MB = 0x20_0000,
-}
-
-/// Need to place the enum values also in an array to be able to iterate over them :/
-const BUCKET_SIZES: [MemBucketSize; 16] = [
-  MemBucketSize::_64B,
-  MemBucketSize::_128B,
-  MemBucketSize::_256B,
-  MemBucketSize::_512B,
-  MemBucketSize::_1KB,
-  MemBucketSize::_2KB,
-  MemBucketSize::_4KB,
-  MemBucketSize::_8KB,
-  MemBucketSize::_16KB,
-  MemBucketSize::_32KB,
-  MemBucketSize::_64KB,
-  MemBucketSize::_128KB,
-  MemBucketSize::_256KB,
-  MemBucketSize::_512KB,
-  MemBucketSize::_1MB,
-  MemBucketSize::_2MB,
-];
+pub(crate) const MM_MAGIC: u32 = 0xDEAD_BEEF;
+
+/// The magic identifier for a managed memory block that has been freed and waits for re-usage
+pub(crate) const MM_FREE_MAGIC: u32 = 0xF4EE_B10C;
+
+/// The size of the canary redzone placed in front of and behind each payload to detect buffer over- and underruns
+#[cfg(feature = "guard-bytes")]
+pub(crate) const GUARD_SIZE: usize = 16;
+#[cfg(not(feature = "guard-bytes"))]
+pub(crate) const GUARD_SIZE: usize = 0;
+
+/// The offset from the payload address back to the location storing the address of the managing descriptor
+pub(crate) const LINK_OFFSET: usize = core::mem::size_of::<usize>() + GUARD_SIZE;
+
+// Memory allocations happens in predefined chunk sizes. This might lead to memory wast in some cases
+// but this could help increasing the speed for re-usage of freed memory regions as we know which
+// bucket to look for when re-using. Memory requirements above the largest bucket size are handled individually
+// w/o any bucket assignment. The bucket sizes are configured at build time, see the build script for details.
+//
+// The generated table is: const BUCKET_SIZES: [usize; _] = [...]; together with const SUB_CLASS_BITS: u32 = _;
+// The build script ensures that no two neighbouring bucket sizes share a size class.
+include!(concat!(env!("OUT_DIR"), "/buckets.rs"));
+
+/// The number of size classes covering all possible sizes
+const SIZE_CLASSES: usize = ((usize::BITS - SUB_CLASS_BITS + 1) as usize) << SUB_CLASS_BITS;
+
+/// The first bucket that is larger than the smallest size of each size class. This allows to find the bucket of any
+/// size in constant time regardless of the number of configured buckets
+const BUCKET_LOOKUP: [u8; SIZE_CLASSES] = bucket_lookup();
 
 extern "C" {
   /// Linker Symbol which address points to the HEAP START.
@@ -74,31 +60,35 @@ extern "C" {
 /// one + the size of this descriptor
 #[repr(C, packed)]
 #[derive(Copy, Clone, Default, Debug)]
-struct MemoryDescriptor {
+pub(crate) struct MemoryDescriptor {
   /// The magic of this block
-  magic: u32,
+  pub(crate) magic: u32,
   /// The bucket index this memory block is assigned to
-  bucket: usize,
+  pub(crate) bucket: usize,
   /// The real occupied memory size (descriptor size + payload size)
-  size: usize,
-  align: usize,
+  pub(crate) size: usize,
+  pub(crate) align: usize,
+  /// The payload size requested by the caller of this memory block
+  pub(crate) req_size: usize,
+  /// The generation of the HEAP this memory block has been allocated in, see [crate::mark]
+  pub(crate) generation: u32,
   /// Address of the preceding memory block when this one is ready for re-use
-  prev: usize,
+  pub(crate) prev: usize,
   /// Address of the following memory block when this one is ready for re-use
-  next: usize,
+  pub(crate) next: usize,
   /// payload address. In addition the address of the descritor managing this memory need to be
   /// stored relative to the address stored here to ensure we can calculate the descriptor address
   /// back from the payload address in case we were ask to free this location
-  payload_addr: usize,
+  pub(crate) payload_addr: usize,
   /// this placeholder ensures that the payload starts earliest after this usize field. If this is
   /// the case this field will contain the address of the descriptor which need to be stored relative
   /// to the payload start address
   _placeholder: usize,
 }
 
-struct BucketQueue {
-  head: AtomicUsize,
-  tail: AtomicUsize,
+pub(crate) struct BucketQueue {
+  pub(crate) head: AtomicUsize,
+  pub(crate) tail: AtomicUsize,
 }
 
 /// The global pointer to the next free memory location on the HEAP not considering re-usage. If no
@@ -106,114 +96,243 @@ struct BucketQueue {
 /// ``usize`` to ensure we can perform immediate atomic math operation (add/sub) on it.
 static HEAP_START: AtomicUsize = AtomicUsize::new(0);
 
+/// An empty list of re-usable memory blocks
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_QUEUE: BucketQueue = BucketQueue {
+  head: AtomicUsize::new(0),
+  tail: AtomicUsize::new(0),
+};
+
 /// The list of buckets that may contain re-usable memory blocks. The new free memory blocks are added always to the
 /// tail of each list, while the retrival always happens from the head. Like FIFO buffer
-static FREE_BUCKETS: [BucketQueue; BUCKET_SIZES.len() + 1] = [
-  BucketQueue {
-    head: AtomicUsize::new(0),
-    tail: AtomicUsize::new(0),
-  },
-  BucketQueue {
-    head: AtomicUsize::new(0),
-    tail: AtomicUsize::new(0),
-  },
-  BucketQueue {
-    head: AtomicUsize::new(0),
-    tail: AtomicUsize::new(0),
-  },
-  BucketQueue {
-    head: AtomicUsize::new(0),
-    tail: AtomicUsize::new(0),
-  },
-  BucketQueue {
-    head: AtomicUsize::new(0),
-    tail: AtomicUsize::new(0),
-  },
-  BucketQueue {
-    head: AtomicUsize::new(0),
-    tail: AtomicUsize::new(0),
-  },
-  BucketQueue {
-    head: AtomicUsize::new(0),
-    tail: AtomicUsize::new(0),
-  },
-  BucketQueue {
-    head: AtomicUsize::new(0),
-    tail: AtomicUsize::new(0),
-  },
-  BucketQueue {
-    head: AtomicUsize::new(0),
-    tail: AtomicUsize::new(0),
-  },
-  BucketQueue {
-    head: AtomicUsize::new(0),
-    tail: AtomicUsize::new(0),
-  },
-  BucketQueue {
-    head: AtomicUsize::new(0),
-    tail: AtomicUsize::new(0),
-  },
-  BucketQueue {
-    head: AtomicUsize::new(0),
-    tail: AtomicUsize::new(0),
-  },
-  BucketQueue {
-    head: AtomicUsize::new(0),
-    tail: AtomicUsize::new(0),
-  },
-  BucketQueue {
-    head: AtomicUsize::new(0),
-    tail: AtomicUsize::new(0),
-  },
-  BucketQueue {
-    head: AtomicUsize::new(0),
-    tail: AtomicUsize::new(0),
-  },
-  BucketQueue {
-    head: AtomicUsize::new(0),
-    tail: AtomicUsize::new(0),
-  },
-  BucketQueue {
-    head: AtomicUsize::new(0),
-    tail: AtomicUsize::new(0),
-  },
-];
+pub(crate) static FREE_BUCKETS: [BucketQueue; BUCKET_SIZES.len() + 1] =
+  [EMPTY_QUEUE; BUCKET_SIZES.len() + 1];
+
+/// Bitmap of the buckets that may contain re-usable memory blocks. The bit of a bucket is set once a memory block is
+/// pushed to its list and cleared once its list is found to be empty. This allows to find the next larger bucket with
+/// re-usable memory blocks without visiting each list. The dynamically sized memory blocks are not tracked here.
+static NON_EMPTY_BUCKETS: AtomicU64 = AtomicU64::new(0);
+
+/// The memory occupied by live memory blocks including their descriptors
+static USED: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of live memory blocks
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// The current generation of the HEAP stored in each memory block allocated. It is advanced with each [crate::mark]
+pub(crate) static GENERATION: AtomicU32 = AtomicU32::new(0);
+
+/// Set the HEAP_START to the address provided by the linker script if this has not happened yet
+#[inline]
+fn init_heap_start() {
+  let _ = HEAP_START.compare_exchange(0, heap_bottom(), Ordering::SeqCst, Ordering::Relaxed);
+}
+
+/// The address of the first memory block managed on the HEAP
+#[cfg(not(feature = "std"))]
+#[inline]
+pub(crate) fn heap_bottom() -> usize {
+  unsafe { &__heap_start as *const usize as usize }
+}
+
+/// The address the HEAP shall never grow beyond. On the Raspberry Pi this is where the peripheral memory region starts
+#[cfg(not(feature = "std"))]
+#[inline]
+pub(crate) fn heap_limit() -> usize {
+  0x3f00_0000
+}
+
+/// With the ``std`` feature the HEAP is not provided by the linker script but is a memory region on the host machine
+/// given with [set_heap_region]. This contains its start and end address
+#[cfg(feature = "std")]
+static HEAP_REGION: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+/// The address of the first memory block managed on the HEAP
+#[cfg(feature = "std")]
+#[inline]
+pub(crate) fn heap_bottom() -> usize {
+  HEAP_REGION[0].load(Ordering::Acquire)
+}
+
+/// The address the HEAP shall never grow beyond
+#[cfg(feature = "std")]
+#[inline]
+pub(crate) fn heap_limit() -> usize {
+  HEAP_REGION[1].load(Ordering::Acquire)
+}
+
+/// Use the memory region between the given addresses as HEAP. This resets the whole state of the allocator, so any
+/// memory allocated before is lost.
+///
+/// # Safety
+/// The memory region need to be valid and exclusively used by the allocator as long as it is used as HEAP.
+#[cfg(feature = "std")]
+pub(crate) unsafe fn set_heap_region(start: usize, end: usize) {
+  HEAP_REGION[0].store(start, Ordering::Release);
+  HEAP_REGION[1].store(end, Ordering::Release);
+  HEAP_START.store(start, Ordering::Release);
+  for queue in FREE_BUCKETS.iter() {
+    queue.head.store(0, Ordering::Release);
+    queue.tail.store(0, Ordering::Release);
+  }
+  NON_EMPTY_BUCKETS.store(0, Ordering::Release);
+  USED.store(0, Ordering::Release);
+  ALLOCATIONS.store(0, Ordering::Release);
+  #[cfg(feature = "quarantine")]
+  crate::quarantine::clear();
+  #[cfg(feature = "buddy")]
+  crate::buddy::reset();
+  #[cfg(feature = "tlsf")]
+  crate::tlsf::reset();
+}
+
+/// The address of the next free memory location at the end of the HEAP. All memory blocks ever handed out are located
+/// between [heap_bottom] and this address
+#[inline]
+pub(crate) fn heap_top() -> usize {
+  match HEAP_START.load(Ordering::Acquire) {
+    0 => heap_bottom(),
+    top => top,
+  }
+}
+
+/// The size of the memory blocks assigned to the given bucket. Memory blocks with the bucket index
+/// ``BUCKET_SIZES.len()`` are sized individually and ``None`` is returned for them.
+pub(crate) fn bucket_size(bucket: usize) -> Option<usize> {
+  BUCKET_SIZES.get(bucket).copied()
+}
+
+/// The size class of the given size calculated from its leading zero count. Each power of two is divided into
+/// ``1 << SUB_CLASS_BITS`` size classes of equal width
+#[inline]
+const fn size_class(size: usize) -> usize {
+  if size < 1 << SUB_CLASS_BITS {
+    return size;
+  }
+  let msb = usize::BITS - 1 - size.leading_zeros();
+  let sub_class = (size >> (msb - SUB_CLASS_BITS)) & ((1 << SUB_CLASS_BITS) - 1);
+  (((msb - SUB_CLASS_BITS + 1) as usize) << SUB_CLASS_BITS) | sub_class
+}
+
+/// The smallest size falling into the given size class
+const fn size_class_start(class: usize) -> usize {
+  if class < 1 << SUB_CLASS_BITS {
+    return class;
+  }
+  let msb = (class >> SUB_CLASS_BITS) as u32 + SUB_CLASS_BITS - 1;
+  let sub_class = class & ((1 << SUB_CLASS_BITS) - 1);
+  ((1 << SUB_CLASS_BITS) | sub_class) << (msb - SUB_CLASS_BITS)
+}
+
+/// Build the [BUCKET_LOOKUP] table from the configured bucket sizes
+const fn bucket_lookup() -> [u8; SIZE_CLASSES] {
+  let mut lookup = [0; SIZE_CLASSES];
+  let mut class = 0;
+  let mut bucket = 0;
+  while class < SIZE_CLASSES {
+    let start = size_class_start(class);
+    while bucket < BUCKET_SIZES.len() && BUCKET_SIZES[bucket] <= start {
+      bucket += 1;
+    }
+    lookup[class] = bucket as u8;
+    class += 1;
+  }
+  lookup
+}
+
+/// The smallest bucket a memory block of the given physical size fits into. ``BUCKET_SIZES.len()`` is returned if the
+/// size exceeds all buckets and the memory block need to be sized individually
+#[inline]
+fn bucket_for(size: usize) -> usize {
+  // the lookup gives the first bucket larger than the start of the size class. If a bucket size lies within the size
+  // class the following bucket might be the one to use. As the build script ensures that the bucket sizes are not
+  // closer to each other than the width of the size classes this loop runs at most once
+  let mut bucket = BUCKET_LOOKUP[size_class(size)] as usize;
+  while bucket < BUCKET_SIZES.len() && size >= BUCKET_SIZES[bucket] {
+    bucket += 1;
+  }
+  bucket
+}
+
+/// Walk all memory blocks located on the HEAP, live and freed ones, in the order of their addresses. As the blocks
+/// follow each other without gaps the next block is found using the size of the current one. The given function
+/// receives the address and a copy of the descriptor of each block and returns whether the walk shall continue.
+///
+/// The walk stops at the first descriptor that does not carry a known magic or a size that does not fit into the
+/// HEAP as the location of the following block could not be trusted any more. In this case the address of this
+/// descriptor is returned as error.
+///
+/// This gives only a consistent picture if no other core is allocating or freeing memory while walking the HEAP.
+pub(crate) fn walk_heap<F>(mut f: F) -> Result<(), usize>
+where
+  F: FnMut(usize, &MemoryDescriptor) -> bool,
+{
+  let top = heap_top();
+  let mut descriptor_addr = heap_bottom();
+  while descriptor_addr < top {
+    let descriptor = unsafe { *(descriptor_addr as *const MemoryDescriptor) };
+    let trusted = (descriptor.magic == MM_MAGIC || descriptor.magic == MM_FREE_MAGIC)
+      && descriptor.size >= core::mem::size_of::<MemoryDescriptor>()
+      && descriptor.size <= top - descriptor_addr;
+    if !f(descriptor_addr, &descriptor) {
+      return Ok(());
+    }
+    if !trusted {
+      return Err(descriptor_addr);
+    }
+    descriptor_addr += descriptor.size;
+  }
+
+  Ok(())
+}
+
+/// The largest number of memory blocks the HEAP is able to hold. Following a list of memory blocks is bounded by this,
+/// so a corrupted list containing a cycle is never followed forever
+pub(crate) fn max_heap_blocks() -> usize {
+  (heap_top() - heap_bottom()) / core::mem::size_of::<MemoryDescriptor>()
+}
+
+/// Returns whether a descriptor located at the given address lies completely within the HEAP, so it could be read
+/// safely while following a list of memory blocks that might be corrupted
+pub(crate) fn within_heap(block: usize) -> bool {
+  block >= heap_bottom()
+    && matches!(
+      block.checked_add(core::mem::size_of::<MemoryDescriptor>()),
+      Some(end) if end <= heap_top()
+    )
+}
 
 /// Allocate an arbitrary size of memory on the HEAP
 /// The alignment is given in Bytes and need to be a power of 2
-pub(crate) fn alloc(req_size: usize, alignment: usize) -> *mut u8 {
+pub(crate) fn try_alloc(req_size: usize, alignment: usize) -> Result<NonNull<u8>, AllocError> {
   // if the HEAP START is initial (0) set the address from the linker script
-  let _ = HEAP_START.compare_exchange(
-    0,
-    unsafe { &__heap_start as *const usize as usize },
-    Ordering::SeqCst,
-    Ordering::Relaxed,
-  );
+  init_heap_start();
 
-  // calculate the required size to be allocated including descriptor size and alignment
+  // calculate the required size to be allocated including descriptor size, guard bytes and alignment
   let padding = alignment; //1 << alignment;
-  let admin_size = core::mem::size_of::<MemoryDescriptor>() + padding;
+  let admin_size = core::mem::size_of::<MemoryDescriptor>() + GUARD_SIZE + padding;
   // calculate the physical size in memory that is required to be allocated
-  let phys_size = admin_size + req_size;
+  let phys_size = req_size
+    .checked_add(admin_size + GUARD_SIZE)
+    .ok_or(AllocError::OutOfMemory)?;
 
   // the physical size defines the bucket this allocation will fall into, so get the smallest bucket
   // where this size would fit
-  let bucket_idx = BUCKET_SIZES
-    .iter()
-    .position(|&bucket| phys_size < bucket as usize);
+  let bucket = bucket_for(phys_size);
 
   // if a bucket could be found allocate its size, otherwise allocate the requested size w/o a bucket assignment
-  let alloc_size = bucket_idx.map_or(phys_size, |b| BUCKET_SIZES[b] as usize);
-  let bucket = bucket_idx.unwrap_or_else(|| BUCKET_SIZES.len());
+  let alloc_size = bucket_size(bucket).unwrap_or(phys_size);
 
   // check if we can get the next position to allocate memory from a re-usable bucket.
   // if this is not the case we retrieve this from the end of the current heap. Both is crucial to
   // get right in the concurrent/multicore access scenario
-  let descriptor_addr = pop_from_free_bucket(bucket, alloc_size)
-    .unwrap_or_else(|| HEAP_START.fetch_add(alloc_size, Ordering::SeqCst));
-  //let descriptor_addr = HEAP_START.fetch_add(alloc_size, Ordering::SeqCst);
+  let (descriptor_addr, bucket, alloc_size) = match take_free_block(bucket, alloc_size)? {
+    Some((descriptor_addr, reused_bucket)) => {
+      (descriptor_addr, reused_bucket, BUCKET_SIZES[reused_bucket])
+    }
+    None => (grow_heap(alloc_size)?, bucket, alloc_size),
+  };
 
-  assert!(descriptor_addr < 0x3f00_0000);
   // any other concurrent allocation will now see the new HEAP_START, so we can now maintain the
   // descriptor at the given location
   let descriptor = unsafe { &mut *(descriptor_addr as *mut MemoryDescriptor) };
@@ -223,89 +342,191 @@ pub(crate) fn alloc(req_size: usize, alignment: usize) -> *mut u8 {
   descriptor.bucket = bucket;
   descriptor.size = alloc_size;
   descriptor.align = alignment;
+  descriptor.req_size = req_size;
+  descriptor.generation = GENERATION.load(Ordering::Relaxed);
   descriptor.prev = 0;
   descriptor.next = 0;
   descriptor._placeholder = 0;
   descriptor.payload_addr = (descriptor_addr + admin_size) & !(padding - 1);
-  assert!(descriptor.payload_addr > descriptor_addr + core::mem::size_of::<MemoryDescriptor>());
+  assert!(
+    descriptor.payload_addr
+      > descriptor_addr + core::mem::size_of::<MemoryDescriptor>() + GUARD_SIZE
+  );
 
   // the usable address is stored in the payload attribute of the descriptor, however,
   // while releasing memory with this address given, we need a way to calculate the MemoryDescriptor location from
   // there. This is done by keeping at least 1 ``usize`` location free in front of the usage
-  // memory location and store the descriptor address there
-  let descriptor_link_store = descriptor.payload_addr - core::mem::size_of::<usize>();
+  // memory location (and its front guard bytes) and store the descriptor address there
+  let descriptor_link_store = descriptor.payload_addr - LINK_OFFSET;
   unsafe { *(descriptor_link_store as *mut usize) = descriptor_addr };
+  USED.fetch_add(alloc_size, Ordering::Relaxed);
+  ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+  #[cfg(feature = "guard-bytes")]
+  crate::guard::arm(descriptor);
+  #[cfg(feature = "poison")]
+  crate::poison::fill_fresh(descriptor);
+  #[cfg(feature = "trace")]
+  crate::trace::record(
+    crate::trace::TraceOp::Alloc,
+    descriptor.payload_addr,
+    req_size,
+    alignment,
+    bucket,
+  );
   // now hand out the actual payload address pointing to the allocated memory with at least the requested size
-  descriptor.payload_addr as *mut u8
+  Ok(unsafe { NonNull::new_unchecked(descriptor.payload_addr as *mut u8) })
+}
+
+/// Take memory of the given size from the end of the HEAP. As we need to update the HEAP_START to let others know
+/// where to request memory from, this is done in a loop until no other core has changed the HEAP_START in between
+fn grow_heap(size: usize) -> Result<usize, AllocError> {
+  let mut descriptor_addr = HEAP_START.load(Ordering::Acquire);
+  loop {
+    let heap_end = match descriptor_addr.checked_add(size) {
+      Some(heap_end) if heap_end <= heap_limit() => heap_end,
+      _ => return Err(AllocError::OutOfMemory),
+    };
+    match HEAP_START.compare_exchange_weak(
+      descriptor_addr,
+      heap_end,
+      Ordering::SeqCst,
+      Ordering::Acquire,
+    ) {
+      Ok(_) => return Ok(descriptor_addr),
+      Err(current) => descriptor_addr = current,
+    }
+  }
 }
 
 /// allocate memory in chunks of pages, where the page size depends on the architecture and is therefore given from the
 /// caller. It always allocates memory that is alligned to the page boundaries and occupies (num * page_size) memory on
-/// the heap
+/// the heap. A null pointer is returned if the pages do not fit into the HEAP any more
 #[allow(dead_code)]
 pub(crate) fn alloc_page(num: usize, page_size: usize) -> *mut u8 {
   // for the time beeing we will always allocate fresh memory from the heap for this kind of allocation
   // and do never check available free buckets
   // if the HEAP START is initial (0) set the address from the linker script
-  let _ = HEAP_START.compare_exchange(
-    0,
-    unsafe { &__heap_start as *const usize as usize },
-    Ordering::SeqCst,
-    Ordering::Relaxed,
-  );
-
-  // from the current HEAP_START calculate the next start address of a page
-  let mut heap_start = HEAP_START.load(Ordering::Acquire);
-  let heap_align = (heap_start + page_size - 1) & !(page_size - 1);
-  // if the aligned address does not allow enough space for the memory descriptor we need
-  // "waste" some memory and go to the next page start address
-  if (heap_align - heap_start) < core::mem::size_of::<MemoryDescriptor>() {
-    heap_start = heap_align + page_size;
-  } else {
-    heap_start = heap_align;
-  }
-
-  // as we now know where the requested memory will start and end we can update the HEAP_START accordingly to let
-  // others know where to request memory from
-  HEAP_START.store(heap_start + num * page_size, Ordering::Release); // from her other cores will be able to access
-                                                                     // this as well
+  init_heap_start();
+
+  // the descriptor is placed at the current HEAP_START while the payload starts at the next page boundary that
+  // leaves enough space for the descriptor and the front guard bytes. Any memory "wasted" for the alignment belongs
+  // to this block, so the blocks on the heap always follow each other without gaps.
+  // As we need to update the HEAP_START to let others know where to request memory from, this is done in a loop
+  // until no other core has changed the HEAP_START in between. The HEAP_START is only advanced if the pages fit into
+  // the HEAP, so a failing allocation leaves the HEAP untouched
+  let mut descriptor_addr = HEAP_START.load(Ordering::Acquire);
+  let (payload_addr, heap_end) = loop {
+    let pages = descriptor_addr
+      .checked_add(core::mem::size_of::<MemoryDescriptor>() + GUARD_SIZE + page_size - 1)
+      .map(|addr| addr & !(page_size - 1))
+      .and_then(|payload_addr| {
+        num
+          .checked_mul(page_size)
+          .and_then(|size| payload_addr.checked_add(size + GUARD_SIZE))
+          .map(|heap_end| (payload_addr, heap_end))
+      });
+    let (payload_addr, heap_end) = match pages {
+      Some((payload_addr, heap_end)) if heap_end <= heap_limit() => (payload_addr, heap_end),
+      _ => return core::ptr::null_mut(),
+    };
+    match HEAP_START.compare_exchange(
+      descriptor_addr,
+      heap_end,
+      Ordering::SeqCst,
+      Ordering::Acquire,
+    ) {
+      Ok(_) => break (payload_addr, heap_end),
+      Err(current) => descriptor_addr = current,
+    }
+  };
 
-  let alloc_size = num * page_size + core::mem::size_of::<MemoryDescriptor>();
-  let descriptor_addr = heap_start - core::mem::size_of::<MemoryDescriptor>();
   // fill the descriptor structure
   let descriptor = unsafe { &mut *(descriptor_addr as *mut MemoryDescriptor) };
 
   // now fill the memory descriptor managing this allocation
   descriptor.magic = MM_MAGIC;
   descriptor.bucket = BUCKET_SIZES.len();
-  descriptor.size = alloc_size;
+  descriptor.size = heap_end - descriptor_addr;
   descriptor.align = page_size;
+  descriptor.req_size = num * page_size;
+  descriptor.generation = GENERATION.load(Ordering::Relaxed);
   descriptor.prev = 0;
   descriptor.next = 0;
   descriptor._placeholder = 0;
-  descriptor.payload_addr = heap_start;
-  assert!(descriptor.payload_addr < 0x3f00_0000);
+  descriptor.payload_addr = payload_addr;
 
   // the usable address is stored in the payload attribute of the descriptor, however,
   // while releasing memory with this address given, we need a way to calculate the MemoryDescriptor location from
   // there. This is done by keeping at least 1 ``usize`` location free in front of the usage
-  // memory location and store the descriptor address there
-  let descriptor_link_store = descriptor.payload_addr - core::mem::size_of::<usize>();
+  // memory location (and its front guard bytes) and store the descriptor address there
+  let descriptor_link_store = descriptor.payload_addr - LINK_OFFSET;
   unsafe { *(descriptor_link_store as *mut usize) = descriptor_addr };
+  USED.fetch_add(heap_end - descriptor_addr, Ordering::Relaxed);
+  ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+  #[cfg(feature = "guard-bytes")]
+  crate::guard::arm(descriptor);
+  #[cfg(feature = "trace")]
+  crate::trace::record(
+    crate::trace::TraceOp::AllocPage,
+    payload_addr,
+    num * page_size,
+    page_size,
+    BUCKET_SIZES.len(),
+  );
   //info!("{:#x?} -> {:#x?}, linkstore: {:#x?}", descriptor_addr, descriptor, descriptor_link_store);
   // now hand out the actual payload address pointing to the allocated memory with at least the requested size
   descriptor.payload_addr as *mut u8
 }
 
+/// Get the descriptor managing the memory block of the given payload pointer
+///
+/// # Safety
+/// The payload pointer need to be handed out by this allocator and not freed yet
+#[allow(dead_code)]
+pub(crate) unsafe fn descriptor(address: *mut u8) -> &'static MemoryDescriptor {
+  let descriptor_link_store = (address as usize) - LINK_OFFSET;
+  &*(*(descriptor_link_store as *const usize) as *const MemoryDescriptor)
+}
+
 /// Free the memory occupied by the given payload pointer
 pub(crate) fn free(address: *mut u8) {
   // first get the address of the descriptor for this payload pointer
-  let descriptor_link_store = (address as usize) - core::mem::size_of::<usize>();
+  let descriptor_link_store = (address as usize) - LINK_OFFSET;
   let descriptor_addr = unsafe { *(descriptor_link_store as *const usize) };
-  let mut descriptor = unsafe { &mut *(descriptor_addr as *mut MemoryDescriptor) };
+  let descriptor = unsafe { &mut *(descriptor_addr as *mut MemoryDescriptor) };
   assert!(descriptor.magic == MM_MAGIC);
-  // clean the magic of this memory block
-  descriptor.magic = 0;
+  #[cfg(feature = "trace")]
+  crate::trace::record(
+    crate::trace::TraceOp::Free,
+    address as usize,
+    descriptor.req_size,
+    descriptor.align,
+    descriptor.bucket,
+  );
+  // verify the redzones around the payload are still intact before this block is released
+  #[cfg(feature = "guard-bytes")]
+  if let Some(violation) = crate::guard::check(descriptor_addr, descriptor) {
+    panic!("{}", violation);
+  }
+  USED.fetch_sub(descriptor.size, Ordering::Relaxed);
+  ALLOCATIONS.fetch_sub(1, Ordering::Relaxed);
+  // mark this memory block as freed and poison its memory
+  descriptor.magic = MM_FREE_MAGIC;
+  #[cfg(feature = "poison")]
+  crate::poison::fill_freed(descriptor_addr, descriptor);
+  // while the quarantine is active the memory block is not released immediately but takes the place of the oldest
+  // one waiting in the quarantine. Only this one is released for re-usage
+  #[cfg(feature = "quarantine")]
+  let descriptor_addr = match crate::quarantine::enqueue(descriptor_addr) {
+    Some(evicted) => evicted,
+    None => return,
+  };
+  release(descriptor_addr);
+}
+
+/// Release a freed memory block for re-usage
+pub(crate) fn release(descriptor_addr: usize) {
+  let descriptor = unsafe { &mut *(descriptor_addr as *mut MemoryDescriptor) };
   // we now know the data of this memory descriptor, add this one to the corresponding free bucket
   // or just adjust the heap pointer if this is the last memory entry that is about to be freed
   let heap_check = descriptor_addr + descriptor.size;
@@ -364,14 +585,112 @@ fn push_to_free_bucket(descriptor: &mut MemoryDescriptor) {
           .head
           .store(descriptor_addr, Ordering::SeqCst);
       }
+      // 7. let others know this bucket contains re-usable memory blocks
+      if descriptor.bucket < BUCKET_SIZES.len() {
+        NON_EMPTY_BUCKETS.fetch_or(1 << descriptor.bucket, Ordering::AcqRel);
+      }
       return;
     }
   }
 }
 
-// get the next free re-usable bucket to allocate the memory from
+/// Take a re-usable memory block for the given bucket. If there is none in this bucket the memory block is taken from
+/// the next larger bucket containing re-usable memory blocks and split into smaller ones if possible. Returns the
+/// address of the memory block and its bucket.
+#[inline]
+fn take_free_block(bucket: usize, alloc_size: usize) -> Result<Option<(usize, usize)>, AllocError> {
+  if let Some(descriptor_addr) = pop_from_free_bucket(bucket, alloc_size)? {
+    return Ok(Some((descriptor_addr, bucket)));
+  }
+  if bucket >= BUCKET_SIZES.len() {
+    return Ok(None);
+  }
+  // all buckets larger than the requested one that might contain re-usable memory blocks, the smallest one first
+  let mut candidates = NON_EMPTY_BUCKETS.load(Ordering::Acquire) & (!1u64 << bucket);
+  while candidates != 0 {
+    let larger_bucket = candidates.trailing_zeros() as usize;
+    if let Some(descriptor_addr) = pop_from_free_bucket(larger_bucket, BUCKET_SIZES[larger_bucket])?
+    {
+      let bucket = split_free_block(descriptor_addr, larger_bucket, bucket);
+      return Ok(Some((descriptor_addr, bucket)));
+    }
+    candidates &= candidates - 1;
+  }
+  Ok(None)
+}
+
+/// Split a re-usable memory block of a larger bucket that has been taken for an allocation of the given bucket. The
+/// front of the memory block is used for the allocation while the remainder is divided into memory blocks of the
+/// largest fitting buckets that are pushed to their lists. As all memory blocks on the HEAP need to follow each other
+/// without gaps the memory block is only split if the remainder could be divided completely. Returns the bucket the
+/// memory block used for the allocation is assigned to.
+fn split_free_block(descriptor_addr: usize, block_bucket: usize, bucket: usize) -> usize {
+  let block_end = descriptor_addr + BUCKET_SIZES[block_bucket];
+  let split_start = descriptor_addr + BUCKET_SIZES[bucket];
+  if !split_pieces(block_end - split_start, |_| ()) {
+    // keep the memory block as a whole
+    return block_bucket;
+  }
+
+  let mut piece_addr = split_start;
+  split_pieces(block_end - split_start, |piece_bucket| {
+    // the memory of the piece is already part of a freed memory block, so it only requires its descriptor
+    let piece = unsafe { &mut *(piece_addr as *mut MemoryDescriptor) };
+    *piece = MemoryDescriptor {
+      magic: MM_FREE_MAGIC,
+      bucket: piece_bucket,
+      size: BUCKET_SIZES[piece_bucket],
+      ..Default::default()
+    };
+    piece_addr += BUCKET_SIZES[piece_bucket];
+    push_to_free_bucket(piece);
+  });
+
+  bucket
+}
+
+/// Divide the given size into the largest fitting buckets starting with the largest one. The given function receives
+/// the bucket of each piece. Returns whether the size could be divided completely.
+#[inline]
+fn split_pieces<F: FnMut(usize)>(mut remainder: usize, mut f: F) -> bool {
+  while remainder > 0 {
+    // the bucket below the one this size fits into is the largest one that fits into this size. Only memory blocks
+    // able to hold a descriptor are created
+    match bucket_for(remainder).checked_sub(1) {
+      Some(piece_bucket)
+        if BUCKET_SIZES[piece_bucket] >= core::mem::size_of::<MemoryDescriptor>() =>
+      {
+        f(piece_bucket);
+        remainder -= BUCKET_SIZES[piece_bucket];
+      }
+      _ => return false,
+    }
+  }
+  true
+}
+
+/// Returns whether the descriptor at the given address describes a freed memory block of the given bucket
+#[inline]
+fn is_free_block(descriptor_addr: usize, bucket: usize) -> bool {
+  let descriptor = unsafe { &*(descriptor_addr as *const MemoryDescriptor) };
+  let (magic, block_bucket) = (descriptor.magic, descriptor.bucket);
+  magic == MM_FREE_MAGIC && block_bucket == bucket
+}
+
+/// Clear the bit of the given bucket in [NON_EMPTY_BUCKETS] after its list was found to be empty. If another core
+/// pushed a memory block in between the bit is restored
+#[inline]
+fn mark_empty(bucket: usize) {
+  NON_EMPTY_BUCKETS.fetch_and(!(1 << bucket), Ordering::AcqRel);
+  if FREE_BUCKETS[bucket].head.load(Ordering::Acquire) != 0 {
+    NON_EMPTY_BUCKETS.fetch_or(1 << bucket, Ordering::AcqRel);
+  }
+}
+
+// get the next free re-usable bucket to allocate the memory from. A memory block taken from the list that is not a
+// freed one of this bucket reveals a corrupted list
 #[inline]
-fn pop_from_free_bucket(bucket: usize, _alloc_size: usize) -> Option<usize> {
+fn pop_from_free_bucket(bucket: usize, _alloc_size: usize) -> Result<Option<usize>, AllocError> {
   assert!(bucket < FREE_BUCKETS.len());
   // TODO: dynamically sized buckets need special treatment to see if the requested size will fit into one. This
   // is not yet properly tested, so for the time beeing any dynamically sized freed bucket will never be re-used
@@ -400,14 +719,14 @@ fn pop_from_free_bucket(bucket: usize, _alloc_size: usize) -> Option<usize> {
                 // we could split the region up into another re-usable memory block
                 let remaining_size = descriptor.size -  alloc_size;
                 // check only if at least 64Bytes are remaining
-                if remaining_size > MemBucketSize::_64B {
+                if remaining_size > BUCKET_SIZES[0] {
                     // create a new memory descriptor located after the memory we re-use
                     let mut descriptor = unsafe {
                         &mut *((reusable_bucket + alloc_size) as *mut MemoryDescriptor)
                     };
                     let bucket_idx = BUCKET_SIZES
                         .iter()
-                        .position(|&bucket| remaining_size < bucket as usize);
+                        .position(|&bucket| remaining_size < bucket);
                     let bucket = bucket_idx.unwrap_or_else(|| BUCKET_SIZES.len());
 
                     descriptor.bucket = bucket;
@@ -429,13 +748,22 @@ fn pop_from_free_bucket(bucket: usize, _alloc_size: usize) -> Option<usize> {
     }
     */
     // no reusable memory block found --> trigger allocation from fresh heap ...
-    return None;
+    return Ok(None);
   } else {
     // first check if we have re-usable memory available in the corresponding bucket
     let reusable_bucket = FREE_BUCKETS[bucket].head.load(Ordering::Acquire);
     // if this is available use it as the free slot, so replace this free bucket with it's next
     // one. This is crucial in cuncurrent access so do this only if this still is the same free bucket
     if reusable_bucket != 0 {
+      // the links of a corrupted memory block shall not be followed, so it is validated before it is taken from the
+      // list. The list is left untouched in this case. If the head has changed in between another core has taken the
+      // memory block and it is no corruption
+      if !within_heap(reusable_bucket) || !is_free_block(reusable_bucket, bucket) {
+        if FREE_BUCKETS[bucket].head.load(Ordering::Acquire) != reusable_bucket {
+          return Ok(None);
+        }
+        return Err(AllocError::Corruption(reusable_bucket));
+      }
       let descriptor = unsafe { &*(reusable_bucket as *const MemoryDescriptor) };
       if FREE_BUCKETS[bucket]
         .head
@@ -454,16 +782,118 @@ fn pop_from_free_bucket(bucket: usize, _alloc_size: usize) -> Option<usize> {
         } else {
           // clear the tail as this was the last entry in the list
           FREE_BUCKETS[bucket].tail.store(0, Ordering::SeqCst);
+          mark_empty(bucket);
+        }
+        // verify nobody has written to this memory block since it was freed
+        #[cfg(feature = "poison")]
+        if let Some(violation) = crate::poison::check_freed(reusable_bucket, descriptor) {
+          panic!("{}", violation);
         }
         // use the reusable bucket as new memory block
-        return Some(reusable_bucket);
+        return Ok(Some(reusable_bucket));
       } else {
         // the re-usable bucket has been occupied since the last read, so continue with
         // allocating from the heap
-        return None;
+        return Ok(None);
+      }
+    }
+    mark_empty(bucket);
+  }
+
+  Ok(None)
+}
+
+/// The bucket allocator as backend of the global allocator
+pub(crate) struct BucketBackend;
+
+impl Backend for BucketBackend {
+  /// Alignments beyond 1 GB could never be served on a Raspberry Pi
+  const MAX_ALIGN: usize = 1 << 30;
+
+  fn try_alloc(&self, size: usize, align: usize) -> Result<NonNull<u8>, AllocError> {
+    try_alloc(size, align)
+  }
+
+  unsafe fn free(&self, ptr: *mut u8) {
+    free(ptr)
+  }
+
+  unsafe fn usable_size(&self, ptr: *mut u8) -> usize {
+    // the rear redzone directly follows the requested size, so the rounding of the memory block is not usable then
+    if cfg!(feature = "guard-bytes") {
+      return descriptor(ptr).req_size;
+    }
+    let descriptor_addr = *((ptr as usize - LINK_OFFSET) as *const usize);
+    descriptor_addr + descriptor(ptr).size - ptr as usize
+  }
+
+  unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    if new_size > self.usable_size(ptr) {
+      let new_ptr = self.alloc(new_size, layout.align());
+      if !new_ptr.is_null() {
+        core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+        free(ptr);
       }
+      return new_ptr;
+    }
+    // the memory block stays in place, only the requested size changes and the rear redzone moves along with it
+    let descriptor_addr = *((ptr as usize - LINK_OFFSET) as *const usize);
+    let descriptor = &mut *(descriptor_addr as *mut MemoryDescriptor);
+    descriptor.req_size = new_size;
+    #[cfg(feature = "guard-bytes")]
+    crate::guard::arm(descriptor);
+    ptr
+  }
+
+  fn stats(&self) -> HeapStats {
+    HeapStats {
+      heap_size: heap_top() - heap_bottom(),
+      used: USED.load(Ordering::Relaxed),
+      allocations: ALLOCATIONS.load(Ordering::Relaxed),
     }
   }
+}
 
-  None
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// The bucket of the given size found by searching all bucket sizes
+  fn linear_bucket_for(size: usize) -> usize {
+    BUCKET_SIZES
+      .iter()
+      .position(|&bucket| size < bucket)
+      .unwrap_or(BUCKET_SIZES.len())
+  }
+
+  #[test]
+  fn size_class_start_is_inverse() {
+    for class in 0..SIZE_CLASSES {
+      assert_eq!(size_class(size_class_start(class)), class);
+    }
+    for size in (0..1 << 16).chain((usize::MAX - 1024)..=usize::MAX) {
+      let class = size_class(size);
+      assert!(size_class_start(class) <= size);
+      assert!(class + 1 == SIZE_CLASSES || size < size_class_start(class + 1));
+    }
+  }
+
+  #[test]
+  fn bucket_for_matches_linear_search() {
+    let largest = BUCKET_SIZES[BUCKET_SIZES.len() - 1];
+    for size in 0..=largest.min(1 << 16) {
+      assert_eq!(bucket_for(size), linear_bucket_for(size), "size {}", size);
+    }
+    // the sizes around each bucket boundary, the start of each size class and the largest sizes
+    let boundaries = BUCKET_SIZES
+      .iter()
+      .copied()
+      .chain((0..SIZE_CLASSES).map(size_class_start))
+      .chain(core::iter::once(usize::MAX - 1));
+    for boundary in boundaries {
+      for size in [boundary.saturating_sub(1), boundary, boundary + 1] {
+        assert_eq!(bucket_for(size), linear_bucket_for(size), "size {}", size);
+      }
+    }
+  }
 }
@@ -0,0 +1,153 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # No-Allocation Regions
+//!
+//! Interrupt handlers and hard real-time loops must never allocate. With the ``no-alloc`` feature such code could be
+//! run with [no_alloc] or while a [NoAllocGuard] lives, which marks the current core. Any allocation, re-allocation
+//! or free through the [crate::RusPiRoAllocator] on this core calls the hook set with [set_no_alloc_hook] with the
+//! layout of the operation. Without a hook the violation panics. Each operation is checked only once, even if it
+//! allocates or frees memory on its own.
+//!
+//! While the hook runs the region is lifted, so the hook is able to log the violation even if this allocates.
+//!
+
+use crate::cpu;
+use core::alloc::Layout;
+use core::fmt;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// The allocator operation done within a no-allocation region
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NoAllocOp {
+  /// Memory is about to be allocated
+  Alloc,
+  /// Memory is about to be re-allocated, the layout is the one of the existing memory
+  Realloc,
+  /// Memory is about to be freed
+  Dealloc,
+}
+
+impl fmt::Display for NoAllocOp {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      NoAllocOp::Alloc => write!(f, "allocation"),
+      NoAllocOp::Realloc => write!(f, "re-allocation"),
+      NoAllocOp::Dealloc => write!(f, "free"),
+    }
+  }
+}
+
+/// The hook called with the operation and its layout if the allocator is used within a no-allocation region
+pub type NoAllocHook = fn(NoAllocOp, Layout);
+
+/// The hook stored as function pointer. 0 if no hook is set
+static HOOK: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of no-allocation regions entered on each core
+#[allow(clippy::declare_interior_mutable_const)]
+const OUTSIDE: AtomicUsize = AtomicUsize::new(0);
+static DEPTH: [AtomicUsize; cpu::CORE_COUNT] = [OUTSIDE; cpu::CORE_COUNT];
+
+/// Whether a violation is currently reported on each core
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_REPORTING: AtomicBool = AtomicBool::new(false);
+static REPORTING: [AtomicBool; cpu::CORE_COUNT] = [NOT_REPORTING; cpu::CORE_COUNT];
+
+/// Clears the reporting flag of a core once the violation is reported
+struct Reporting(&'static AtomicBool);
+
+impl Drop for Reporting {
+  fn drop(&mut self) {
+    self.0.store(false, Ordering::Relaxed);
+  }
+}
+
+/// Set the hook called if the allocator is used within a no-allocation region, for example to log the violation or
+/// to count them. The operation is done after the hook returns.
+///
+/// # Example
+/// ```ignore
+/// fn violation(op: NoAllocOp, layout: Layout) {
+///   error!("{} of {} Bytes in an interrupt handler", op, layout.size());
+/// }
+///
+/// ruspiro_allocator::set_no_alloc_hook(violation);
+/// ```
+pub fn set_no_alloc_hook(hook: NoAllocHook) {
+  HOOK.store(hook as usize, Ordering::Release);
+}
+
+/// Guard marking the current core as no-allocation region as long as it lives. Guards could be nested. The guard is
+/// bound to the core it has been created on and could not be sent to another one.
+///
+/// # Example
+/// ```ignore
+/// fn irq_handler() {
+///   let _guard = NoAllocGuard::new();
+///   acknowledge_timer();
+/// }
+/// ```
+pub struct NoAllocGuard {
+  core: usize,
+  // the guard shall be dropped on the core that created it
+  _not_send: PhantomData<*const ()>,
+}
+
+impl NoAllocGuard {
+  /// Mark the current core as no-allocation region
+  #[allow(clippy::new_without_default)]
+  pub fn new() -> Self {
+    let core = cpu::core_id();
+    DEPTH[core].fetch_add(1, Ordering::Relaxed);
+    Self {
+      core,
+      _not_send: PhantomData,
+    }
+  }
+}
+
+impl Drop for NoAllocGuard {
+  fn drop(&mut self) {
+    DEPTH[self.core].fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+/// Run the given closure as no-allocation region on the current core
+///
+/// # Example
+/// ```ignore
+/// let sample = ruspiro_allocator::no_alloc(|| read_sensor(&mut adc));
+/// ```
+pub fn no_alloc<R, F: FnOnce() -> R>(f: F) -> R {
+  let _guard = NoAllocGuard::new();
+  f()
+}
+
+/// Report the given allocator operation if the current core is within a no-allocation region
+#[inline]
+pub(crate) fn check(op: NoAllocOp, layout: Layout) {
+  let core = cpu::core_id();
+  // the region is lifted while reporting the violation, so the hook or the panic handler is able to allocate
+  if DEPTH[core].load(Ordering::Relaxed) == 0 || REPORTING[core].swap(true, Ordering::Relaxed) {
+    return;
+  }
+  let _reporting = Reporting(&REPORTING[core]);
+  match HOOK.load(Ordering::Acquire) {
+    0 => panic!(
+      "{} of {} bytes aligned to {} within a no-allocation region",
+      op,
+      layout.size(),
+      layout.align()
+    ),
+    hook => {
+      let hook: NoAllocHook = unsafe { core::mem::transmute(hook) };
+      hook(op, layout);
+    }
+  }
+}
@@ -0,0 +1,89 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Memory Poisoning
+//!
+//! With the ``poison`` feature active the payload of each new allocation is filled with [POISON_FRESH] and the whole
+//! memory block following its descriptor is filled with [POISON_FREED] before it is put into the list of re-usable
+//! blocks. Once a freed memory block is about to be re-used the freed pattern is verified to be still intact. Any
+//! difference reveals a write to the memory after it has been freed.
+//!
+
+use crate::memory::MemoryDescriptor;
+use core::fmt;
+
+/// The pattern the payload of each new allocation is filled with
+pub const POISON_FRESH: u8 = 0xAA;
+
+/// The pattern a freed memory block is filled with while waiting for re-usage
+pub const POISON_FREED: u8 = 0xDD;
+
+/// Description of a freed memory block that has been written to after it was freed
+#[derive(Copy, Clone, Debug)]
+pub struct PoisonViolation {
+  /// The address of the memory block (its descriptor) that has been written to
+  pub block: usize,
+  /// The bucket index of this memory block
+  pub bucket: usize,
+  /// The address of the first byte that does no longer contain the freed pattern
+  pub address: usize,
+  /// The value found at this address
+  pub value: u8,
+}
+
+impl fmt::Display for PoisonViolation {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "freed memory block {:#x} (bucket {}) written after free at {:#x}: found {:#04x} instead of {:#04x}",
+      self.block, self.bucket, self.address, self.value, POISON_FREED
+    )
+  }
+}
+
+/// Fill the payload of a new allocation with the fresh pattern
+pub(crate) fn fill_fresh(descriptor: &MemoryDescriptor) {
+  unsafe {
+    core::ptr::write_bytes(
+      descriptor.payload_addr as *mut u8,
+      POISON_FRESH,
+      descriptor.req_size,
+    );
+  }
+}
+
+/// Fill the whole memory block following the descriptor with the freed pattern
+pub(crate) fn fill_freed(block: usize, descriptor: &MemoryDescriptor) {
+  let start = block + core::mem::size_of::<MemoryDescriptor>();
+  unsafe {
+    core::ptr::write_bytes(
+      start as *mut u8,
+      POISON_FREED,
+      descriptor.size - core::mem::size_of::<MemoryDescriptor>(),
+    );
+  }
+}
+
+/// Verify the freed pattern of the memory block following the descriptor is still intact
+pub(crate) fn check_freed(block: usize, descriptor: &MemoryDescriptor) -> Option<PoisonViolation> {
+  let start = block + core::mem::size_of::<MemoryDescriptor>();
+  let memory = unsafe {
+    core::slice::from_raw_parts(
+      start as *const u8,
+      descriptor.size - core::mem::size_of::<MemoryDescriptor>(),
+    )
+  };
+  memory
+    .iter()
+    .position(|&byte| byte != POISON_FREED)
+    .map(|offset| PoisonViolation {
+      block,
+      bucket: descriptor.bucket,
+      address: start + offset,
+      value: memory[offset],
+    })
+}
@@ -0,0 +1,109 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Memory Pressure
+//!
+//! With the ``memory-pressure`` feature callbacks could be registered that release memory, like dropping caches or
+//! flushing buffers, once the HEAP is exhausted. Before an allocation fails the callbacks are called in the order of
+//! their priority, the highest one first. Each time a callback reports that it released memory the allocation is
+//! retried.
+//!
+//! A callback is never re-entered. If it allocates memory itself while the HEAP is exhausted it is skipped for this
+//! allocation. The same applies if another core currently runs it.
+//!
+
+use crate::backend::AllocError;
+use crate::lock::SpinLock;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// The maximum number of memory pressure callbacks that could be registered at the same time
+pub const MAX_PRESSURE_CALLBACKS: usize = 8;
+
+/// A callback releasing memory. It receives the size of the allocation that failed and returns whether it was able
+/// to release some memory
+pub type PressureCallback = fn(usize) -> bool;
+
+/// The handle of a registered memory pressure callback required to unregister it again
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PressureHandle(usize);
+
+/// A registered callback together with its priority
+#[derive(Copy, Clone)]
+struct Registration {
+  priority: u8,
+  callback: PressureCallback,
+}
+
+static CALLBACKS: SpinLock<[Option<Registration>; MAX_PRESSURE_CALLBACKS]> =
+  SpinLock::new([None; MAX_PRESSURE_CALLBACKS]);
+
+/// The flags marking the callbacks that currently run
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_RUNNING: AtomicBool = AtomicBool::new(false);
+static RUNNING: [AtomicBool; MAX_PRESSURE_CALLBACKS] = [NOT_RUNNING; MAX_PRESSURE_CALLBACKS];
+
+/// Register a callback that is called to release memory before an allocation fails. Callbacks with a higher priority
+/// are called first. Returns ``None`` if [MAX_PRESSURE_CALLBACKS] callbacks are already registered.
+///
+/// # Example
+/// ```ignore
+/// fn drop_texture_cache(_size: usize) -> bool {
+///   TEXTURES.lock().clear();
+///   true
+/// }
+///
+/// let handle = ruspiro_allocator::register_pressure_callback(10, drop_texture_cache).unwrap();
+/// ```
+pub fn register_pressure_callback(
+  priority: u8,
+  callback: PressureCallback,
+) -> Option<PressureHandle> {
+  let mut callbacks = CALLBACKS.lock();
+  let slot = callbacks.iter().position(Option::is_none)?;
+  callbacks[slot] = Some(Registration { priority, callback });
+  Some(PressureHandle(slot))
+}
+
+/// Unregister the memory pressure callback of the given handle
+pub fn unregister_pressure_callback(handle: PressureHandle) {
+  CALLBACKS.lock()[handle.0] = None;
+}
+
+/// Call the memory pressure callbacks after the HEAP could not serve an allocation of the given size. The allocation
+/// is retried with the given function each time a callback released memory until it no longer fails for lack of
+/// memory.
+pub(crate) fn reclaim<F>(size: usize, mut alloc: F) -> Result<NonNull<u8>, AllocError>
+where
+  F: FnMut() -> Result<NonNull<u8>, AllocError>,
+{
+  // the callbacks are called without holding the lock, so they are able to allocate and to register callbacks
+  let callbacks = *CALLBACKS.lock();
+  let mut called = [false; MAX_PRESSURE_CALLBACKS];
+  // the next callback to call is the one with the highest priority not called yet
+  while let Some((slot, registration)) = callbacks
+    .iter()
+    .enumerate()
+    .filter(|(slot, _)| !called[*slot])
+    .filter_map(|(slot, registration)| registration.map(|registration| (slot, registration)))
+    .max_by_key(|(slot, registration)| (registration.priority, usize::MAX - slot))
+  {
+    called[slot] = true;
+    if RUNNING[slot].swap(true, Ordering::AcqRel) {
+      continue;
+    }
+    let released = (registration.callback)(size);
+    RUNNING[slot].store(false, Ordering::Release);
+    if released {
+      match alloc() {
+        Err(AllocError::OutOfMemory) => (),
+        result => return result,
+      }
+    }
+  }
+  Err(AllocError::OutOfMemory)
+}
@@ -0,0 +1,101 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Quarantine For Freed Memory Blocks
+//!
+//! With the ``quarantine`` feature active a freed memory block is not released for re-usage immediately. It is put
+//! into a bounded FIFO instead and takes the place of the oldest memory block waiting there. Only this evicted block is
+//! released for re-usage. This delays the re-usage of freed memory and increases the chance that writes after free
+//! are detected by the poison pattern, which is verified again once a memory block leaves the quarantine.
+//!
+
+use crate::memory::{self, MemoryDescriptor};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The number of freed memory blocks kept in the quarantine
+pub(crate) const QUARANTINE_SLOTS: usize = 64;
+
+/// An empty slot of the quarantine
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: AtomicUsize = AtomicUsize::new(0);
+
+/// The slots of the quarantine containing the addresses of the freed memory blocks (their descriptors)
+static QUARANTINE: [AtomicUsize; QUARANTINE_SLOTS] = [EMPTY_SLOT; QUARANTINE_SLOTS];
+
+/// The ever increasing position of the next slot in the quarantine to be used. Each slot is used in turn, so the
+/// slot taken next always contains the oldest memory block
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+
+/// Put the freed memory block into the quarantine. If this evicts the oldest memory block from the quarantine its
+/// poison pattern is verified and its address returned to be released for re-usage.
+pub(crate) fn enqueue(block: usize) -> Option<usize> {
+  // claiming the slot and swapping its content are atomic operations on their own. This ensures each evicted memory
+  // block is seen by exactly one caller, even if several cores are freeing memory at the same time
+  let slot = NEXT_SLOT.fetch_add(1, Ordering::AcqRel) % QUARANTINE_SLOTS;
+  match QUARANTINE[slot].swap(block, Ordering::AcqRel) {
+    0 => None,
+    evicted => {
+      verify(evicted);
+      Some(evicted)
+    }
+  }
+}
+
+/// Release all memory blocks currently waiting in the quarantine for re-usage. Their poison pattern is verified before
+/// doing so.
+pub fn flush_quarantine() {
+  for slot in QUARANTINE.iter() {
+    match slot.swap(0, Ordering::AcqRel) {
+      0 => (),
+      evicted => {
+        verify(evicted);
+        memory::release(evicted);
+      }
+    }
+  }
+}
+
+/// Returns the number of memory blocks currently waiting in the quarantine
+pub(crate) fn count() -> usize {
+  QUARANTINE
+    .iter()
+    .filter(|slot| slot.load(Ordering::Acquire) != 0)
+    .count()
+}
+
+/// Forget all memory blocks waiting in the quarantine without releasing them
+#[cfg(feature = "std")]
+pub(crate) fn clear() {
+  for slot in QUARANTINE.iter() {
+    slot.store(0, Ordering::Release);
+  }
+}
+
+/// Returns whether the given memory block is currently waiting in the quarantine
+pub(crate) fn contains(block: usize) -> bool {
+  QUARANTINE
+    .iter()
+    .any(|slot| slot.load(Ordering::Acquire) == block)
+}
+
+/// Call the given function with the address of each memory block currently waiting in the quarantine
+pub(crate) fn for_each<F: FnMut(usize)>(mut f: F) {
+  for slot in QUARANTINE.iter() {
+    match slot.load(Ordering::Acquire) {
+      0 => (),
+      block => f(block),
+    }
+  }
+}
+
+/// Verify nobody has written to the memory block while it was waiting in the quarantine
+fn verify(block: usize) {
+  let descriptor = unsafe { &*(block as *const MemoryDescriptor) };
+  if let Some(violation) = crate::poison::check_freed(block, descriptor) {
+    panic!("{}", violation);
+  }
+}
@@ -0,0 +1,114 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Newlib Support
+//!
+//! The ``malloc`` of newlib grows its own heap with ``_sbrk``. With the ``sbrk`` feature this function is exported
+//! and hands out memory of a region reserved from the allocator, so the heaps of the Rust and the C code do not
+//! collide. The region is allocated once ``_sbrk`` is called the first time. Its size is configured at build time
+//! with the environment variable ``RUSPIRO_ALLOCATOR_SBRK_SIZE``, see the build script for details.
+//!
+
+#[cfg(any(feature = "buddy", feature = "tlsf"))]
+use crate::backend::{Backend, BACKEND};
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// The generated constant is: const SBRK_SIZE: usize = ...;
+include!(concat!(env!("OUT_DIR"), "/sbrk.rs"));
+
+/// The alignment of the reserved region
+const SBRK_ALIGN: usize = 4096;
+
+/// The value returned by ``_sbrk`` if the break could not be moved, ``(void *)-1``
+const SBRK_FAILED: *mut c_void = usize::MAX as *mut c_void;
+
+/// The start address of the reserved region, 0 as long as it is not allocated
+static SBRK_START: AtomicUsize = AtomicUsize::new(0);
+
+/// The current program break within the reserved region
+static SBRK_BREAK: AtomicUsize = AtomicUsize::new(0);
+
+/// Move the program break by the given number of bytes and return the previous one. Returns ``(void *)-1`` if the
+/// break would leave the reserved region or the region could not be allocated
+#[cfg_attr(not(any(test, doctest, feature = "std")), no_mangle)]
+pub extern "C" fn _sbrk(increment: isize) -> *mut c_void {
+  let start = match reserved_region() {
+    Some(start) => start,
+    None => return SBRK_FAILED,
+  };
+  let mut current = SBRK_BREAK.load(Ordering::Acquire);
+  loop {
+    let new_break = if increment < 0 {
+      current.checked_sub(increment.unsigned_abs())
+    } else {
+      current.checked_add(increment as usize)
+    };
+    let new_break = match new_break {
+      Some(new_break) if new_break >= start && new_break <= start + SBRK_SIZE => new_break,
+      _ => return SBRK_FAILED,
+    };
+    match SBRK_BREAK.compare_exchange_weak(current, new_break, Ordering::AcqRel, Ordering::Acquire)
+    {
+      Ok(_) => return current as *mut c_void,
+      Err(actual) => current = actual,
+    }
+  }
+}
+
+/// The start address of the reserved region. It is allocated by the first caller. If several cores race for this the
+/// one setting the initial break wins, the others free their region again and wait for the start to be published
+fn reserved_region() -> Option<usize> {
+  match SBRK_START.load(Ordering::Acquire) {
+    0 => (),
+    start => return Some(start),
+  }
+  let region = reserve() as usize;
+  if region == 0 {
+    return None;
+  }
+  if SBRK_BREAK
+    .compare_exchange(0, region, Ordering::AcqRel, Ordering::Acquire)
+    .is_ok()
+  {
+    SBRK_START.store(region, Ordering::Release);
+    return Some(region);
+  }
+  release(region as *mut u8);
+  loop {
+    match SBRK_START.load(Ordering::Acquire) {
+      0 => core::hint::spin_loop(),
+      start => return Some(start),
+    }
+  }
+}
+
+/// Reserve the region from the bucket allocator. It is taken as pages from the end of the HEAP, as rounding it up to
+/// a bucket would waste up to the size of the region
+#[cfg(not(any(feature = "buddy", feature = "tlsf")))]
+fn reserve() -> *mut u8 {
+  let pages = ((SBRK_SIZE + SBRK_ALIGN - 1) & !(SBRK_ALIGN - 1)) / SBRK_ALIGN;
+  crate::memory::alloc_page(pages, SBRK_ALIGN)
+}
+
+/// Reserve the region from the allocator backend
+#[cfg(any(feature = "buddy", feature = "tlsf"))]
+fn reserve() -> *mut u8 {
+  BACKEND.alloc(SBRK_SIZE, SBRK_ALIGN)
+}
+
+/// Give the reserved region back if another core has been faster to reserve it
+#[cfg(not(any(feature = "buddy", feature = "tlsf")))]
+fn release(region: *mut u8) {
+  crate::memory::free(region)
+}
+
+/// Give the reserved region back if another core has been faster to reserve it
+#[cfg(any(feature = "buddy", feature = "tlsf"))]
+fn release(region: *mut u8) {
+  unsafe { BACKEND.free(region) }
+}
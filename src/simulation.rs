@@ -0,0 +1,96 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Simulated Heap
+//!
+//! With the ``std`` feature the allocator could be run on the host machine on a simulated HEAP. This allows to
+//! evaluate the allocator logic, for example by replaying an allocation trace recorded on the Raspberry Pi, without
+//! flashing a board. The allocator state is global, so only one simulated HEAP could be used at a time.
+//!
+
+use crate::backend::Backend;
+use crate::memory::{self, BucketBackend};
+use crate::snapshot;
+
+/// A memory region on the host machine used as simulated HEAP
+pub struct SimulatedHeap {
+  region: Vec<u8>,
+}
+
+impl SimulatedHeap {
+  /// Create a new simulated HEAP of the given size in bytes and use it as the HEAP of the allocator. This resets the
+  /// whole state of the allocator, so any memory allocated on a previously created simulated HEAP is lost.
+  pub fn new(size: usize) -> Self {
+    let mut region = vec![0u8; size];
+    let start = region.as_mut_ptr() as usize;
+    unsafe { memory::set_heap_region(start, start + size) };
+    Self { region }
+  }
+
+  /// The start address of the simulated HEAP
+  pub fn start(&self) -> usize {
+    self.region.as_ptr() as usize
+  }
+
+  /// The size of the simulated HEAP in bytes
+  pub fn size(&self) -> usize {
+    self.region.len()
+  }
+
+  /// The number of bytes currently occupied by the HEAP. This includes any freed memory block that has not been
+  /// returned to the end of the HEAP.
+  pub fn used(&self) -> usize {
+    memory::heap_top() - memory::heap_bottom()
+  }
+
+  /// Allocate memory of the given size and alignment on the simulated HEAP. Returns a null pointer if the simulated
+  /// HEAP is exhausted
+  pub fn alloc(&mut self, size: usize, align: usize) -> *mut u8 {
+    BucketBackend.alloc(size, align)
+  }
+
+  /// Allocate the given number of pages of the given size on the simulated HEAP. Returns a null pointer if the
+  /// simulated HEAP is exhausted
+  pub fn alloc_page(&mut self, num: usize, page_size: usize) -> *mut u8 {
+    memory::alloc_page(num, page_size)
+  }
+
+  /// The bucket the memory block of the given address is assigned to
+  ///
+  /// # Safety
+  /// The address need to be returned from a previous call to [SimulatedHeap::alloc] or [SimulatedHeap::alloc_page]
+  /// of this simulated HEAP that has not been freed yet.
+  pub unsafe fn bucket(&self, address: *mut u8) -> usize {
+    memory::descriptor(address).bucket
+  }
+
+  /// Free memory allocated on the simulated HEAP
+  ///
+  /// # Safety
+  /// The address need to be returned from a previous call to [SimulatedHeap::alloc] or [SimulatedHeap::alloc_page]
+  /// of this simulated HEAP that has not been freed yet.
+  pub unsafe fn free(&mut self, address: *mut u8) {
+    memory::free(address)
+  }
+
+  /// Write a binary snapshot of the simulated HEAP that could be parsed with [SnapshotReader](crate::SnapshotReader).
+  /// The snapshot is passed in small chunks to the given function. Returns the total number of bytes written or the
+  /// first error returned by the given function.
+  pub fn write_snapshot<F, E>(&self, write: F) -> Result<usize, E>
+  where
+    F: FnMut(&[u8]) -> Result<(), E>,
+  {
+    snapshot::write_snapshot(write)
+  }
+}
+
+impl Drop for SimulatedHeap {
+  fn drop(&mut self) {
+    // the allocator shall not use the memory region any longer once it is released
+    unsafe { memory::set_heap_region(0, 0) };
+  }
+}
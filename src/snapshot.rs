@@ -0,0 +1,516 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Binary Heap Snapshot
+//!
+//! Serialize the state of the allocator into a compact binary snapshot that could be send over UART or written to an
+//! SD card for later analysis on a host machine. The snapshot is written without any heap memory allocation.
+//!
+//! Only the state of the bucket allocator could be serialized, so writing a snapshot is not available with the
+//! ``buddy`` or ``tlsf`` feature. The snapshot of a ``SimulatedHeap`` could be written with any feature, as the
+//! simulation always runs the bucket allocator.
+//!
+//! All values are stored in little endian byte order. A snapshot starts with a header followed by a stream of
+//! records, each starting with a single tag byte:
+//!
+//! Field         | Size      | Description
+//! --------------|-----------|------------------------------------------------------------------
+//! magic         | 4         | ``b"RPHS"``
+//! version       | 2         | [SNAPSHOT_VERSION]
+//! heap start    | 8         | start address of the HEAP
+//! heap end      | 8         | address of the next free memory location at the end of the HEAP
+//! bucket count  | 2         | number of buckets, followed by the size of each bucket (8 bytes each, 0 for dynamically sized blocks)
+//!
+//! Record        | Tag       | Content
+//! --------------|-----------|------------------------------------------------------------------
+//! block         | 0x01      | address (8), size (8), bucket (2), magic (4), payload address (8), requested size (8)
+//! free entry    | 0x02      | bucket (2), address (8), previous (8), next (8)
+//! walk aborted  | 0x03      | address (8) of the corrupted memory block the HEAP could not be walked behind
+//! quarantined   | 0x04      | address (8) of a freed memory block waiting in the quarantine, see the ``quarantine`` feature
+//! end           | 0xFF      | number of block records (4), number of free entry records (4)
+//!
+
+use crate::memory::{self, MemoryDescriptor, FREE_BUCKETS, MM_FREE_MAGIC};
+use core::fmt;
+use core::sync::atomic::Ordering;
+
+/// The magic each snapshot starts with
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"RPHS";
+
+/// The version of the snapshot format written by [write_snapshot]
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+const TAG_BLOCK: u8 = 0x01;
+const TAG_FREE_ENTRY: u8 = 0x02;
+const TAG_WALK_ABORTED: u8 = 0x03;
+#[cfg_attr(not(feature = "quarantine"), allow(dead_code))]
+const TAG_QUARANTINED: u8 = 0x04;
+const TAG_END: u8 = 0xFF;
+
+/// Write a snapshot of the current allocator state. The snapshot is passed in small chunks to the given function,
+/// which could forward them to the UART or a file. Returns the total number of bytes written or the first error
+/// returned by the given function.
+///
+/// This gives only a consistent picture if no other core is allocating or freeing memory while taking the snapshot.
+///
+/// # Example
+/// ```ignore
+/// # use ruspiro_allocator::write_snapshot;
+/// let written = write_snapshot(|chunk| {
+///   // send the chunk to the UART of your choice
+///   let _ = chunk;
+///   Ok::<(), ()>(())
+/// });
+/// ```
+#[cfg_attr(
+  all(any(feature = "buddy", feature = "tlsf"), not(feature = "std")),
+  allow(dead_code)
+)]
+pub fn write_snapshot<F, E>(write: F) -> Result<usize, E>
+where
+  F: FnMut(&[u8]) -> Result<(), E>,
+{
+  let mut writer = SnapshotWriter { write, written: 0 };
+
+  writer.bytes(&SNAPSHOT_MAGIC)?;
+  writer.u16(SNAPSHOT_VERSION)?;
+  writer.u64(memory::heap_bottom() as u64)?;
+  writer.u64(memory::heap_top() as u64)?;
+  writer.u16(FREE_BUCKETS.len() as u16)?;
+  for bucket in 0..FREE_BUCKETS.len() {
+    writer.u64(memory::bucket_size(bucket).unwrap_or(0) as u64)?;
+  }
+
+  let mut result = Ok(());
+  let mut blocks = 0u32;
+  let mut free_blocks = 0u32;
+  let walked = memory::walk_heap(|block, descriptor| {
+    result = writer.block(block, descriptor);
+    blocks += 1;
+    if descriptor.magic == MM_FREE_MAGIC {
+      free_blocks += 1;
+    }
+    result.is_ok()
+  });
+  result?;
+  if let Err(block) = walked {
+    writer.u8(TAG_WALK_ABORTED)?;
+    writer.u64(block as u64)?;
+  }
+
+  // do not follow a list with more entries than freed memory blocks exist, it most likely contains a cycle. If the
+  // HEAP could not be walked completely the number of freed memory blocks is not known, so the size of the HEAP bounds
+  // the list. An entry outside the HEAP ends the list as its descriptor could not be read safely
+  let max_entries = match walked {
+    Ok(()) => free_blocks,
+    Err(_) => memory::max_heap_blocks() as u32,
+  };
+  let mut free_entries = 0u32;
+  for (bucket, queue) in FREE_BUCKETS.iter().enumerate() {
+    let mut block = queue.head.load(Ordering::Acquire);
+    let mut entries = 0;
+    while block != 0 && entries < max_entries && memory::within_heap(block) {
+      let descriptor = unsafe { *(block as *const MemoryDescriptor) };
+      writer.u8(TAG_FREE_ENTRY)?;
+      writer.u16(bucket as u16)?;
+      writer.u64(block as u64)?;
+      writer.u64(descriptor.prev as u64)?;
+      writer.u64(descriptor.next as u64)?;
+      entries += 1;
+      block = descriptor.next;
+    }
+    free_entries += entries;
+  }
+
+  #[cfg(feature = "quarantine")]
+  {
+    let mut result = Ok(());
+    crate::quarantine::for_each(|block| {
+      if result.is_ok() {
+        result = writer
+          .u8(TAG_QUARANTINED)
+          .and_then(|_| writer.u64(block as u64));
+      }
+    });
+    result?;
+  }
+
+  writer.u8(TAG_END)?;
+  writer.u32(blocks)?;
+  writer.u32(free_entries)?;
+
+  Ok(writer.written)
+}
+
+/// Helper to write the little endian encoded values of a snapshot
+struct SnapshotWriter<F> {
+  write: F,
+  written: usize,
+}
+
+impl<F, E> SnapshotWriter<F>
+where
+  F: FnMut(&[u8]) -> Result<(), E>,
+{
+  fn bytes(&mut self, bytes: &[u8]) -> Result<(), E> {
+    self.written += bytes.len();
+    (self.write)(bytes)
+  }
+
+  fn u8(&mut self, value: u8) -> Result<(), E> {
+    self.bytes(&[value])
+  }
+
+  fn u16(&mut self, value: u16) -> Result<(), E> {
+    self.bytes(&value.to_le_bytes())
+  }
+
+  fn u32(&mut self, value: u32) -> Result<(), E> {
+    self.bytes(&value.to_le_bytes())
+  }
+
+  fn u64(&mut self, value: u64) -> Result<(), E> {
+    self.bytes(&value.to_le_bytes())
+  }
+
+  fn block(&mut self, block: usize, descriptor: &MemoryDescriptor) -> Result<(), E> {
+    self.u8(TAG_BLOCK)?;
+    self.u64(block as u64)?;
+    self.u64(descriptor.size as u64)?;
+    self.u16(descriptor.bucket as u16)?;
+    self.u32(descriptor.magic)?;
+    self.u64(descriptor.payload_addr as u64)?;
+    self.u64(descriptor.req_size as u64)
+  }
+}
+
+/// Errors that might occur while reading a snapshot
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SnapshotError {
+  /// The data does not start with the snapshot magic
+  BadMagic,
+  /// The snapshot has been written with a version of the format that is not supported
+  UnsupportedVersion(u16),
+  /// The snapshot ends unexpectedly
+  Truncated,
+  /// The snapshot contains a record with an unknown tag
+  UnknownRecord(u8),
+  /// The snapshot contains values that could not have been written by the allocator
+  Corrupted,
+}
+
+impl fmt::Display for SnapshotError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      SnapshotError::BadMagic => write!(f, "not a heap snapshot"),
+      SnapshotError::UnsupportedVersion(version) => {
+        write!(f, "unsupported heap snapshot version {}", version)
+      }
+      SnapshotError::Truncated => write!(f, "heap snapshot is truncated"),
+      SnapshotError::UnknownRecord(tag) => {
+        write!(f, "heap snapshot contains an unknown record {:#04x}", tag)
+      }
+      SnapshotError::Corrupted => write!(f, "heap snapshot is corrupted"),
+    }
+  }
+}
+
+/// A single record of a snapshot
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SnapshotRecord {
+  /// A memory block found while walking the HEAP
+  Block {
+    address: u64,
+    size: u64,
+    bucket: u16,
+    magic: u32,
+    payload: u64,
+    req_size: u64,
+  },
+  /// An entry of a free bucket list
+  FreeEntry {
+    bucket: u16,
+    address: u64,
+    prev: u64,
+    next: u64,
+  },
+  /// The HEAP could not be walked behind the corrupted memory block at the given address
+  WalkAborted { address: u64 },
+  /// The freed memory block at the given address waits in the quarantine and is not re-usable yet
+  Quarantined { address: u64 },
+  /// The end of the snapshot
+  End { blocks: u32, free_entries: u32 },
+}
+
+impl SnapshotRecord {
+  /// Returns whether the memory block of a [SnapshotRecord::Block] is live
+  pub fn is_live(&self) -> bool {
+    matches!(self, SnapshotRecord::Block { magic, .. } if *magic == memory::MM_MAGIC)
+  }
+
+  /// Returns whether the memory block of a [SnapshotRecord::Block] is freed. This includes the memory blocks waiting
+  /// in the quarantine, which are listed with [SnapshotRecord::Quarantined] as well
+  pub fn is_free(&self) -> bool {
+    matches!(self, SnapshotRecord::Block { magic, .. } if *magic == MM_FREE_MAGIC)
+  }
+}
+
+/// Read a snapshot written with [write_snapshot] from a byte slice without any heap memory allocation
+#[derive(Clone, Debug)]
+pub struct SnapshotReader<'a> {
+  /// The version of the snapshot format
+  pub version: u16,
+  /// The start address of the HEAP
+  pub heap_start: u64,
+  /// The address of the next free memory location at the end of the HEAP
+  pub heap_end: u64,
+  bucket_sizes: &'a [u8],
+  records: &'a [u8],
+}
+
+impl<'a> SnapshotReader<'a> {
+  /// Read the header of the given snapshot
+  pub fn new(data: &'a [u8]) -> Result<Self, SnapshotError> {
+    let mut cursor = Cursor(data);
+    if cursor.take(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+      return Err(SnapshotError::BadMagic);
+    }
+    let version = cursor.u16()?;
+    if version != SNAPSHOT_VERSION {
+      return Err(SnapshotError::UnsupportedVersion(version));
+    }
+    let heap_start = cursor.u64()?;
+    let heap_end = cursor.u64()?;
+    if heap_end < heap_start {
+      return Err(SnapshotError::Corrupted);
+    }
+    let bucket_count = cursor.u16()? as usize;
+    let bucket_sizes = cursor.take(bucket_count * 8)?;
+
+    Ok(Self {
+      version,
+      heap_start,
+      heap_end,
+      bucket_sizes,
+      records: cursor.0,
+    })
+  }
+
+  /// The number of buckets of the allocator that has written the snapshot
+  pub fn bucket_count(&self) -> usize {
+    self.bucket_sizes.len() / 8
+  }
+
+  /// The size of the memory blocks assigned to the given bucket. ``None`` is returned for the bucket of dynamically
+  /// sized memory blocks and unknown buckets.
+  pub fn bucket_size(&self, bucket: usize) -> Option<u64> {
+    let mut cursor = Cursor(self.bucket_sizes.get(bucket * 8..bucket * 8 + 8)?);
+    cursor.u64().ok().filter(|&size| size != 0)
+  }
+
+  /// Iterate over the records of the snapshot. The iteration stops after the end record or the first error.
+  pub fn records(&self) -> SnapshotRecords<'a> {
+    SnapshotRecords {
+      cursor: Cursor(self.records),
+      done: false,
+    }
+  }
+}
+
+/// Iterator over the records of a snapshot
+#[derive(Clone, Debug)]
+pub struct SnapshotRecords<'a> {
+  cursor: Cursor<'a>,
+  done: bool,
+}
+
+impl<'a> Iterator for SnapshotRecords<'a> {
+  type Item = Result<SnapshotRecord, SnapshotError>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.done {
+      return None;
+    }
+    let record = self.read();
+    self.done = matches!(record, Ok(SnapshotRecord::End { .. }) | Err(_));
+    Some(record)
+  }
+}
+
+impl<'a> SnapshotRecords<'a> {
+  fn read(&mut self) -> Result<SnapshotRecord, SnapshotError> {
+    let cursor = &mut self.cursor;
+    match cursor.u8()? {
+      TAG_BLOCK => Ok(SnapshotRecord::Block {
+        address: cursor.u64()?,
+        size: cursor.u64()?,
+        bucket: cursor.u16()?,
+        magic: cursor.u32()?,
+        payload: cursor.u64()?,
+        req_size: cursor.u64()?,
+      }),
+      TAG_FREE_ENTRY => Ok(SnapshotRecord::FreeEntry {
+        bucket: cursor.u16()?,
+        address: cursor.u64()?,
+        prev: cursor.u64()?,
+        next: cursor.u64()?,
+      }),
+      TAG_WALK_ABORTED => Ok(SnapshotRecord::WalkAborted {
+        address: cursor.u64()?,
+      }),
+      TAG_QUARANTINED => Ok(SnapshotRecord::Quarantined {
+        address: cursor.u64()?,
+      }),
+      TAG_END => Ok(SnapshotRecord::End {
+        blocks: cursor.u32()?,
+        free_entries: cursor.u32()?,
+      }),
+      tag => Err(SnapshotError::UnknownRecord(tag)),
+    }
+  }
+}
+
+/// Helper to read the little endian encoded values of a snapshot
+#[derive(Clone, Debug)]
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+  fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+    if self.0.len() < len {
+      return Err(SnapshotError::Truncated);
+    }
+    let (bytes, rest) = self.0.split_at(len);
+    self.0 = rest;
+    Ok(bytes)
+  }
+
+  fn u8(&mut self) -> Result<u8, SnapshotError> {
+    Ok(self.take(1)?[0])
+  }
+
+  fn u16(&mut self) -> Result<u16, SnapshotError> {
+    let mut bytes = [0; 2];
+    bytes.copy_from_slice(self.take(2)?);
+    Ok(u16::from_le_bytes(bytes))
+  }
+
+  fn u32(&mut self) -> Result<u32, SnapshotError> {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(self.take(4)?);
+    Ok(u32::from_le_bytes(bytes))
+  }
+
+  fn u64(&mut self) -> Result<u64, SnapshotError> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(self.take(8)?);
+    Ok(u64::from_le_bytes(bytes))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  #[cfg(feature = "std")]
+  fn round_trip() {
+    // the allocator state is global, so this is the only test using a simulated HEAP
+    let mut heap = crate::simulation::SimulatedHeap::new(0x10_0000);
+    let blocks: Vec<_> = (1..=4).map(|size| heap.alloc(size * 100, 8)).collect();
+    unsafe {
+      heap.free(blocks[0]);
+      heap.free(blocks[2]);
+    }
+
+    let mut snapshot = Vec::new();
+    let written = write_snapshot(|chunk| {
+      snapshot.extend_from_slice(chunk);
+      Ok::<(), ()>(())
+    });
+    assert_eq!(written, Ok(snapshot.len()));
+    let reader = SnapshotReader::new(&snapshot).unwrap();
+    assert_eq!(reader.version, SNAPSHOT_VERSION);
+    assert_eq!(reader.heap_start, heap.start() as u64);
+    assert_eq!(reader.heap_end, (heap.start() + heap.used()) as u64);
+    assert_eq!(reader.bucket_count(), FREE_BUCKETS.len());
+    for bucket in 0..reader.bucket_count() {
+      assert_eq!(
+        reader.bucket_size(bucket),
+        memory::bucket_size(bucket).map(|size| size as u64)
+      );
+    }
+
+    let records: Vec<_> = reader.records().collect::<Result<_, _>>().unwrap();
+    let live = records.iter().filter(|record| record.is_live()).count();
+    let free = records.iter().filter(|record| record.is_free()).count();
+    let free_entries = records
+      .iter()
+      .filter(|record| matches!(record, SnapshotRecord::FreeEntry { .. }))
+      .count();
+    let quarantined = records
+      .iter()
+      .filter(|record| matches!(record, SnapshotRecord::Quarantined { .. }))
+      .count();
+    assert_eq!((live, free), (2, 2));
+    // a freed memory block is either re-usable or waits in the quarantine
+    assert_eq!(free_entries + quarantined, 2);
+    assert_eq!(
+      records.last(),
+      Some(&SnapshotRecord::End {
+        blocks: 4,
+        free_entries: free_entries as u32
+      })
+    );
+
+    let reader = SnapshotReader::new(&snapshot[..snapshot.len() - 1]).unwrap();
+    assert_eq!(reader.records().last(), Some(Err(SnapshotError::Truncated)));
+  }
+
+  #[test]
+  fn invalid_header() {
+    assert_eq!(
+      SnapshotReader::new(b"RPHX\x01\x00").err(),
+      Some(SnapshotError::BadMagic)
+    );
+    assert_eq!(
+      SnapshotReader::new(b"RPHS\x02\x00").err(),
+      Some(SnapshotError::UnsupportedVersion(2))
+    );
+    assert_eq!(
+      SnapshotReader::new(b"RPHS\x01\x00").err(),
+      Some(SnapshotError::Truncated)
+    );
+
+    let mut header = Vec::from(&SNAPSHOT_MAGIC[..]);
+    header.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+    header.extend_from_slice(&0x2000u64.to_le_bytes());
+    header.extend_from_slice(&0x1000u64.to_le_bytes());
+    header.extend_from_slice(&0u16.to_le_bytes());
+    assert_eq!(
+      SnapshotReader::new(&header).err(),
+      Some(SnapshotError::Corrupted)
+    );
+  }
+
+  #[test]
+  fn unknown_record() {
+    let mut snapshot = Vec::from(&SNAPSHOT_MAGIC[..]);
+    snapshot.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+    snapshot.extend_from_slice(&0x1000u64.to_le_bytes());
+    snapshot.extend_from_slice(&0x1000u64.to_le_bytes());
+    snapshot.extend_from_slice(&0u16.to_le_bytes());
+    snapshot.push(0x42);
+    let reader = SnapshotReader::new(&snapshot).unwrap();
+    assert_eq!(reader.bucket_count(), 0);
+    let mut records = reader.records();
+    assert_eq!(
+      records.next(),
+      Some(Err(SnapshotError::UnknownRecord(0x42)))
+    );
+    assert_eq!(records.next(), None);
+  }
+}
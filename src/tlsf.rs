@@ -0,0 +1,689 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Two-Level Segregated Fit Allocator
+//!
+//! With the ``tlsf`` feature the HEAP is managed by a Two-Level Segregated Fit allocator. The free memory blocks are
+//! kept in lists segregated by their size. The first level divides the sizes into powers of two, the second level
+//! divides each power of two linearly into [SL_COUNT] lists. Two levels of bitmaps allow to find a free memory block
+//! that fits a request with a few bit operations. A freed memory block is immediately merged with its free physical
+//! neighbours. So allocating and freeing memory takes constant time, which makes this backend suitable for code with
+//! real-time requirements.
+//!
+//! Beside being used as global allocator a [Heap] could be set up on any memory region.
+//!
+
+use crate::backend::{AllocError, Backend, HeapStats};
+use crate::lock::SpinLock;
+use crate::memory;
+use core::alloc::Layout;
+use core::ptr::NonNull;
+
+/// The granularity of all memory blocks and the minimal alignment of each payload
+const ALIGN: usize = 16;
+
+/// The size of the header in front of each payload containing the address of the previous physical memory block and
+/// the size of this memory block
+const HEADER_SIZE: usize = 16;
+
+/// The smallest memory block. Once freed it needs to hold the links to the other free memory blocks of its list
+const MIN_BLOCK_SIZE: usize = HEADER_SIZE + 2 * core::mem::size_of::<usize>();
+
+/// The number of bits selecting the second level list within a power of two
+const SL_BITS: u32 = 4;
+
+/// The number of second level lists of each first level
+const SL_COUNT: usize = 1 << SL_BITS;
+
+/// The first level covering all memory blocks up to this power of two is divided linearly
+const FL_SHIFT: u32 = SL_BITS + ALIGN.trailing_zeros();
+
+/// Memory blocks below this size are kept in the linearly divided first level
+const SMALL_BLOCK_SIZE: usize = 1 << FL_SHIFT;
+
+/// Memory blocks need to be smaller than ``1 << FL_MAX``, 2 GB
+const FL_MAX: u32 = 31;
+
+/// The number of first levels
+const FL_COUNT: usize = (FL_MAX - FL_SHIFT + 1) as usize;
+
+/// The flag of the size marking a free memory block
+const FREE_BIT: usize = 0b01;
+
+/// The flag of the size marking the previous physical memory block as free
+const PREV_FREE_BIT: usize = 0b10;
+
+/// The size of the chunks the global allocator takes from the HEAP when it needs to grow, 1 MB
+const GROW_CHUNK: usize = 0x10_0000;
+
+/// The header of each memory block. The links to the other free memory blocks occupy the payload and are only used
+/// while the memory block is free
+#[repr(C)]
+struct Block {
+  /// The address of the previous physical memory block, 0 for the first one of a region
+  prev_phys: usize,
+  /// The size of this memory block including its header together with the flags in the lowest bits
+  size: usize,
+  next_free: usize,
+  prev_free: usize,
+}
+
+impl Block {
+  #[inline]
+  fn at<'a>(address: usize) -> &'a mut Block {
+    unsafe { &mut *(address as *mut Block) }
+  }
+
+  #[inline]
+  fn size(&self) -> usize {
+    self.size & !(ALIGN - 1)
+  }
+
+  #[inline]
+  fn set_size(&mut self, size: usize) {
+    self.size = size | (self.size & (ALIGN - 1));
+  }
+
+  #[inline]
+  fn is_free(&self) -> bool {
+    self.size & FREE_BIT != 0
+  }
+
+  #[inline]
+  fn set_free(&mut self, free: bool) {
+    if free {
+      self.size |= FREE_BIT;
+    } else {
+      self.size &= !FREE_BIT;
+    }
+  }
+
+  #[inline]
+  fn is_prev_free(&self) -> bool {
+    self.size & PREV_FREE_BIT != 0
+  }
+
+  #[inline]
+  fn set_prev_free(&mut self, free: bool) {
+    if free {
+      self.size |= PREV_FREE_BIT;
+    } else {
+      self.size &= !PREV_FREE_BIT;
+    }
+  }
+}
+
+/// A HEAP managed by the Two-Level Segregated Fit allocator. It could be set up on any number of memory regions with
+/// [Heap::add_region]. Allocating and freeing memory takes constant time.
+///
+/// The [Heap] itself is not synchronized, so it needs to be guarded when shared between cores.
+///
+/// # Example
+/// ```ignore
+/// use core::alloc::Layout;
+/// use ruspiro_allocator::Heap;
+///
+/// static mut REGION: [u8; 0x1_0000] = [0; 0x1_0000];
+///
+/// let mut heap = Heap::new();
+/// unsafe { heap.add_region(REGION.as_mut_ptr() as usize, REGION.len()) };
+/// let layout = Layout::from_size_align(100, 8).unwrap();
+/// let memory = heap.alloc(layout).unwrap();
+/// unsafe { heap.free(memory) };
+/// ```
+pub struct Heap {
+  /// Bitmap of the first levels containing free memory blocks
+  fl_bitmap: u32,
+  /// Bitmap of the second level lists containing free memory blocks for each first level
+  sl_bitmap: [u32; FL_COUNT],
+  /// The first free memory block of each list
+  free: [[usize; SL_COUNT]; FL_COUNT],
+  /// The end of the region added last. A region added directly behind it extends the last region
+  region_end: usize,
+  /// The size of all regions added without the bytes trimmed for alignment
+  size: usize,
+  /// The memory occupied by live memory blocks
+  used: usize,
+  /// The number of live memory blocks
+  allocations: usize,
+}
+
+impl Default for Heap {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Heap {
+  /// Create a new [Heap] without any memory
+  pub const fn new() -> Self {
+    Self {
+      fl_bitmap: 0,
+      sl_bitmap: [0; FL_COUNT],
+      free: [[0; SL_COUNT]; FL_COUNT],
+      region_end: 0,
+      size: 0,
+      used: 0,
+      allocations: 0,
+    }
+  }
+
+  /// Add the memory region of the given size starting at the given address to this [Heap]. A region directly
+  /// following the region added last is merged with it.
+  ///
+  /// # Safety
+  /// The memory region need to be valid and exclusively used by this [Heap] as long as it exists. It shall be smaller
+  /// than 2 GB.
+  pub unsafe fn add_region(&mut self, start: usize, size: usize) {
+    // a region wrapping around the end of the address space could not be used at all
+    let end = match start.checked_add(size) {
+      Some(end) => end & !(ALIGN - 1),
+      None => return,
+    };
+    // the alignment might trim a tiny region completely. Otherwise the aligned start does not exceed the aligned end
+    if end <= start {
+      return;
+    }
+    let start = align_up(start, ALIGN);
+    if end - start < MIN_BLOCK_SIZE + HEADER_SIZE {
+      return;
+    }
+    assert!(end - start < 1 << FL_MAX);
+
+    // each region ends with a sentinel, a used memory block without any size. If the new region follows the last one
+    // its sentinel becomes the header of the new memory block
+    let block = if start == self.region_end {
+      start - HEADER_SIZE
+    } else {
+      let block = Block::at(start);
+      block.prev_phys = 0;
+      block.size = 0;
+      start
+    };
+    let sentinel = end - HEADER_SIZE;
+    Block::at(block).set_size(sentinel - block);
+    Block::at(block).set_free(false);
+    let sentinel = Block::at(sentinel);
+    sentinel.prev_phys = block;
+    sentinel.size = 0;
+    self.region_end = end;
+    self.size += end - start;
+
+    self.release(block);
+  }
+
+  /// Allocate memory for the given layout. Returns ``None`` if there is no free memory block large enough
+  pub fn alloc(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+    self.try_alloc(layout).ok()
+  }
+
+  /// Allocate memory for the given layout. Fails if there is no free memory block large enough or a corrupted free
+  /// memory block has been found
+  pub fn try_alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+    let payload_size = (layout
+      .size()
+      .max(1)
+      .checked_add(ALIGN - 1)
+      .ok_or(AllocError::OutOfMemory)?
+      & !(ALIGN - 1))
+      .max(MIN_BLOCK_SIZE - HEADER_SIZE);
+    let block_size = payload_size
+      .checked_add(HEADER_SIZE)
+      .ok_or(AllocError::OutOfMemory)?;
+
+    if layout.align() <= ALIGN {
+      let block = self.take(block_size)?;
+      self.occupy(block, block_size);
+      return Ok(unsafe { NonNull::new_unchecked((block + HEADER_SIZE) as *mut u8) });
+    }
+
+    // for larger alignments the memory block need to leave room for a gap in front of the aligned payload that is
+    // large enough to be kept as free memory block of its own
+    let search_size = block_size
+      .checked_add(layout.align() + MIN_BLOCK_SIZE)
+      .ok_or(AllocError::OutOfMemory)?;
+    let mut block = self.take(search_size)?;
+    let mut payload = align_up(block + HEADER_SIZE, layout.align());
+    if payload != block + HEADER_SIZE && payload - HEADER_SIZE - block < MIN_BLOCK_SIZE {
+      payload = align_up(block + HEADER_SIZE + MIN_BLOCK_SIZE, layout.align());
+    }
+    let gap = payload - HEADER_SIZE - block;
+    if gap > 0 {
+      let size = Block::at(block).size();
+      let aligned = block + gap;
+      Block::at(block).set_size(gap);
+      let aligned_block = Block::at(aligned);
+      aligned_block.prev_phys = block;
+      aligned_block.size = size - gap;
+      aligned_block.set_free(true);
+      aligned_block.set_prev_free(true);
+      Block::at(aligned + size - gap).prev_phys = aligned;
+      self.insert(block);
+      block = aligned;
+    }
+    self.occupy(block, block_size);
+    Ok(unsafe { NonNull::new_unchecked(payload as *mut u8) })
+  }
+
+  /// Free the memory at the given address and merge it with its free physical neighbours
+  ///
+  /// # Safety
+  /// The memory need to be allocated from this [Heap] and not freed yet.
+  pub unsafe fn free(&mut self, memory: NonNull<u8>) {
+    let block = memory.as_ptr() as usize - HEADER_SIZE;
+    assert!(!Block::at(block).is_free());
+    self.used -= Block::at(block).size();
+    self.allocations -= 1;
+    self.release(block);
+  }
+
+  /// The number of bytes usable at the given address of an allocated memory
+  ///
+  /// # Safety
+  /// The memory need to be allocated from this [Heap] and not freed yet.
+  pub unsafe fn usable_size(&self, memory: NonNull<u8>) -> usize {
+    let block = memory.as_ptr() as usize - HEADER_SIZE;
+    block + Block::at(block).size() - memory.as_ptr() as usize
+  }
+
+  /// The statistics of the memory usage of this [Heap]. Its size is the size of all regions added
+  pub fn stats(&self) -> HeapStats {
+    HeapStats {
+      heap_size: self.size,
+      used: self.used,
+      allocations: self.allocations,
+    }
+  }
+
+  /// Take a free memory block of at least the given size out of its list. A memory block in the list that is not
+  /// free or does not belong to this list reveals a corruption
+  fn take(&mut self, size: usize) -> Result<usize, AllocError> {
+    let (fl, sl) = match mapping_search(size).and_then(|(fl, sl)| self.find_suitable(fl, sl)) {
+      Some(list) => list,
+      // all lists whose memory blocks fit for sure are empty, but the first memory block of the list this size
+      // belongs to might be large enough
+      None => match mapping(size) {
+        (fl, sl) if fl < FL_COUNT && self.free[fl][sl] != 0 => (fl, sl),
+        _ => return Err(AllocError::OutOfMemory),
+      },
+    };
+    let block = self.free[fl][sl];
+    if !Block::at(block).is_free() || mapping(Block::at(block).size()) != (fl, sl) {
+      return Err(AllocError::Corruption(block));
+    }
+    if Block::at(block).size() < size {
+      return Err(AllocError::OutOfMemory);
+    }
+    self.remove(block);
+    Ok(block)
+  }
+
+  /// Use the given memory block that has been taken from its list for an allocation of the given size. The memory
+  /// not required is split off as free memory block of its own
+  fn occupy(&mut self, block: usize, size: usize) {
+    let block_size = Block::at(block).size();
+    if block_size >= size + MIN_BLOCK_SIZE {
+      let rest = block + size;
+      let rest_block = Block::at(rest);
+      rest_block.prev_phys = block;
+      rest_block.size = block_size - size;
+      rest_block.set_free(true);
+      let next = Block::at(rest + block_size - size);
+      next.prev_phys = rest;
+      next.set_prev_free(true);
+      Block::at(block).set_size(size);
+      self.insert(rest);
+    } else {
+      Block::at(block + block_size).set_prev_free(false);
+    }
+    Block::at(block).set_free(false);
+    self.used += Block::at(block).size();
+    self.allocations += 1;
+  }
+
+  /// Mark the given memory block as free, merge it with its free physical neighbours and put it into its list
+  fn release(&mut self, mut block: usize) {
+    let size = Block::at(block).size();
+    if Block::at(block).is_prev_free() {
+      let prev = Block::at(block).prev_phys;
+      if Block::at(prev).size() + size < 1 << FL_MAX {
+        self.remove(prev);
+        Block::at(prev).set_size(Block::at(prev).size() + size);
+        block = prev;
+      }
+    }
+    let size = Block::at(block).size();
+    let next = block + size;
+    if Block::at(next).is_free() && size + Block::at(next).size() < 1 << FL_MAX {
+      self.remove(next);
+      Block::at(block).set_size(size + Block::at(next).size());
+    }
+
+    let size = Block::at(block).size();
+    Block::at(block).set_free(true);
+    let next = Block::at(block + size);
+    next.prev_phys = block;
+    next.set_prev_free(true);
+    self.insert(block);
+  }
+
+  /// Put the free memory block at the head of its list
+  fn insert(&mut self, block: usize) {
+    let (fl, sl) = mapping(Block::at(block).size());
+    let head = self.free[fl][sl];
+    let free_block = Block::at(block);
+    free_block.prev_free = 0;
+    free_block.next_free = head;
+    if head != 0 {
+      Block::at(head).prev_free = block;
+    }
+    self.free[fl][sl] = block;
+    self.sl_bitmap[fl] |= 1 << sl;
+    self.fl_bitmap |= 1 << fl;
+  }
+
+  /// Remove the free memory block from its list
+  fn remove(&mut self, block: usize) {
+    let (fl, sl) = mapping(Block::at(block).size());
+    let free_block = Block::at(block);
+    let (prev, next) = (free_block.prev_free, free_block.next_free);
+    if prev != 0 {
+      Block::at(prev).next_free = next;
+    } else {
+      self.free[fl][sl] = next;
+    }
+    if next != 0 {
+      Block::at(next).prev_free = prev;
+    }
+    if self.free[fl][sl] == 0 {
+      self.sl_bitmap[fl] &= !(1 << sl);
+      if self.sl_bitmap[fl] == 0 {
+        self.fl_bitmap &= !(1 << fl);
+      }
+    }
+  }
+
+  /// Find the first non empty list at or above the given one
+  fn find_suitable(&self, fl: usize, sl: usize) -> Option<(usize, usize)> {
+    let sl_map = self.sl_bitmap[fl] & (!0u32 << sl);
+    if sl_map != 0 {
+      return Some((fl, sl_map.trailing_zeros() as usize));
+    }
+    let fl_map = self.fl_bitmap & (!0u32).checked_shl(fl as u32 + 1)?;
+    if fl_map == 0 {
+      return None;
+    }
+    let fl = fl_map.trailing_zeros() as usize;
+    Some((fl, self.sl_bitmap[fl].trailing_zeros() as usize))
+  }
+}
+
+/// The list a free memory block of the given size is kept in
+#[inline]
+fn mapping(size: usize) -> (usize, usize) {
+  if size < SMALL_BLOCK_SIZE {
+    (0, size / (SMALL_BLOCK_SIZE / SL_COUNT))
+  } else {
+    let msb = usize::BITS - 1 - size.leading_zeros();
+    let sl = (size >> (msb - SL_BITS)) ^ SL_COUNT;
+    ((msb - FL_SHIFT + 1) as usize, sl)
+  }
+}
+
+/// The first list whose free memory blocks are all large enough for the given size
+#[inline]
+fn mapping_search(size: usize) -> Option<(usize, usize)> {
+  let size = if size < SMALL_BLOCK_SIZE {
+    size
+  } else {
+    let msb = usize::BITS - 1 - size.leading_zeros();
+    size.checked_add((1 << (msb - SL_BITS)) - 1)?
+  };
+  if size >= 1 << FL_MAX {
+    return None;
+  }
+  Some(mapping(size))
+}
+
+fn align_up(address: usize, align: usize) -> usize {
+  (address + align - 1) & !(align - 1)
+}
+
+/// The [Heap] used as global allocator together with the address the HEAP is taken into account up to
+struct GlobalHeap {
+  heap: Heap,
+  top: usize,
+}
+
+static GLOBAL_HEAP: SpinLock<GlobalHeap> = SpinLock::new(GlobalHeap {
+  heap: Heap::new(),
+  top: 0,
+});
+
+impl GlobalHeap {
+  /// Add the next chunk of the HEAP to the [Heap] that is large enough for the given layout. Returns false if the
+  /// HEAP is exhausted
+  fn grow(&mut self, layout: Layout) -> bool {
+    if self.top == 0 {
+      self.top = align_up(memory::heap_bottom(), ALIGN);
+    }
+    // room for the memory block, a gap for its alignment and the sentinel
+    let required = match layout
+      .size()
+      .checked_add(layout.align() + 2 * MIN_BLOCK_SIZE + 2 * HEADER_SIZE)
+    {
+      Some(required) => align_up(required, ALIGN),
+      None => return false,
+    };
+    let available = memory::heap_limit().saturating_sub(self.top) & !(ALIGN - 1);
+    let chunk = required.max(GROW_CHUNK).min(available);
+    if chunk < required || chunk >= 1 << FL_MAX {
+      return false;
+    }
+    unsafe { self.heap.add_region(self.top, chunk) };
+    self.top += chunk;
+    true
+  }
+}
+
+/// Allocate memory of the given size and alignment from the global [Heap]
+pub(crate) fn try_alloc(size: usize, align: usize) -> Result<NonNull<u8>, AllocError> {
+  let layout =
+    Layout::from_size_align(size, align).map_err(|_| AllocError::InvalidAlignment(align))?;
+  let mut global = GLOBAL_HEAP.lock();
+  match global.heap.try_alloc(layout) {
+    Err(AllocError::OutOfMemory) if global.grow(layout) => global.heap.try_alloc(layout),
+    memory => memory,
+  }
+}
+
+/// Free the memory at the given address of the global [Heap]
+pub(crate) fn free(address: *mut u8) {
+  if let Some(memory) = NonNull::new(address) {
+    unsafe { GLOBAL_HEAP.lock().heap.free(memory) };
+  }
+}
+
+/// Forget about the global [Heap], the next allocation starts over at the bottom of the HEAP
+#[cfg(feature = "std")]
+pub(crate) fn reset() {
+  let mut global = GLOBAL_HEAP.lock();
+  global.heap = Heap::new();
+  global.top = 0;
+}
+
+/// The global [Heap] as backend of the global allocator
+pub(crate) struct TlsfBackend;
+
+impl Backend for TlsfBackend {
+  /// The memory block including the gap in front of the aligned payload need to be smaller than ``1 << FL_MAX``
+  const MAX_ALIGN: usize = 1 << (FL_MAX - 1);
+
+  fn try_alloc(&self, size: usize, align: usize) -> Result<NonNull<u8>, AllocError> {
+    try_alloc(size, align)
+  }
+
+  unsafe fn free(&self, ptr: *mut u8) {
+    free(ptr)
+  }
+
+  unsafe fn usable_size(&self, ptr: *mut u8) -> usize {
+    match NonNull::new(ptr) {
+      Some(memory) => GLOBAL_HEAP.lock().heap.usable_size(memory),
+      None => 0,
+    }
+  }
+
+  fn stats(&self) -> HeapStats {
+    GLOBAL_HEAP.lock().heap.stats()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// The size of the memory regions used by the tests
+  const REGION_SIZE: usize = 0x1_0000;
+
+  /// A memory region on the host machine aligned to [ALIGN] that could be handed to a [Heap]
+  fn region(size: usize) -> Vec<u128> {
+    vec![0; size / core::mem::size_of::<u128>()]
+  }
+
+  fn layout(size: usize, align: usize) -> Layout {
+    Layout::from_size_align(size, align).unwrap()
+  }
+
+  /// The largest payload of a single memory block filling the whole region, which is only available if all free
+  /// memory has been merged again
+  fn largest_payload(size: usize) -> Layout {
+    layout(size - 2 * HEADER_SIZE, ALIGN)
+  }
+
+  #[test]
+  fn tiny_regions_are_skipped() {
+    let mut memory = region(REGION_SIZE);
+    let start = memory.as_mut_ptr() as usize;
+    let mut heap = Heap::new();
+    unsafe {
+      heap.add_region(start, 0);
+      heap.add_region(start + 1, ALIGN - 2);
+      heap.add_region(start + 1, MIN_BLOCK_SIZE + HEADER_SIZE);
+      heap.add_region(usize::MAX - ALIGN, 2 * ALIGN);
+    }
+    assert_eq!(heap.stats().heap_size, 0);
+    assert!(heap.alloc(layout(1, 1)).is_none());
+  }
+
+  #[test]
+  fn misaligned_region_is_trimmed() {
+    let mut memory = region(REGION_SIZE);
+    let start = memory.as_mut_ptr() as usize;
+    let mut heap = Heap::new();
+    unsafe { heap.add_region(start + 1, REGION_SIZE - 1) };
+    assert_eq!(heap.stats().heap_size, REGION_SIZE - ALIGN);
+    let memory = heap.alloc(largest_payload(REGION_SIZE - ALIGN)).unwrap();
+    assert_eq!(memory.as_ptr() as usize % ALIGN, 0);
+  }
+
+  #[test]
+  fn alloc_and_free() {
+    let mut memory = region(REGION_SIZE);
+    let mut heap = Heap::new();
+    unsafe { heap.add_region(memory.as_mut_ptr() as usize, REGION_SIZE) };
+    assert_eq!(heap.stats().heap_size, REGION_SIZE);
+
+    let blocks: Vec<_> = (1..20)
+      .map(|size| heap.alloc(layout(size * 24, 8)).unwrap())
+      .collect();
+    assert_eq!(heap.stats().allocations, blocks.len());
+    for (size, block) in (1..20).zip(blocks.iter()) {
+      assert!(unsafe { heap.usable_size(*block) } >= size * 24);
+      assert_eq!(block.as_ptr() as usize % ALIGN, 0);
+    }
+    for block in blocks {
+      unsafe { heap.free(block) };
+    }
+    assert_eq!(heap.stats().allocations, 0);
+    assert_eq!(heap.stats().used, 0);
+  }
+
+  #[test]
+  fn out_of_memory() {
+    let mut memory = region(REGION_SIZE);
+    let mut heap = Heap::new();
+    unsafe { heap.add_region(memory.as_mut_ptr() as usize, REGION_SIZE) };
+    assert_eq!(
+      heap.try_alloc(layout(REGION_SIZE, ALIGN)),
+      Err(AllocError::OutOfMemory)
+    );
+    assert_eq!(
+      heap.try_alloc(layout(isize::MAX as usize - ALIGN, ALIGN)),
+      Err(AllocError::OutOfMemory)
+    );
+    let memory = heap.alloc(largest_payload(REGION_SIZE)).unwrap();
+    assert!(heap.alloc(layout(1, 1)).is_none());
+    unsafe { heap.free(memory) };
+    assert!(heap.alloc(layout(1, 1)).is_some());
+  }
+
+  #[test]
+  fn freed_neighbours_are_merged() {
+    let mut memory = region(REGION_SIZE);
+    let mut heap = Heap::new();
+    unsafe { heap.add_region(memory.as_mut_ptr() as usize, REGION_SIZE) };
+
+    // free the memory blocks in an order merging with the next, the previous and both neighbours
+    for order in [[1, 0, 2], [0, 2, 1], [2, 1, 0]] {
+      let blocks: Vec<_> = (0..3)
+        .map(|_| heap.alloc(layout(1000, 8)).unwrap())
+        .collect();
+      for index in order {
+        unsafe { heap.free(blocks[index]) };
+      }
+      let memory = heap.alloc(largest_payload(REGION_SIZE)).unwrap();
+      unsafe { heap.free(memory) };
+    }
+  }
+
+  #[test]
+  fn aligned_allocations_keep_the_gap_free() {
+    let mut memory = region(REGION_SIZE);
+    let mut heap = Heap::new();
+    unsafe { heap.add_region(memory.as_mut_ptr() as usize, REGION_SIZE) };
+
+    let small = heap.alloc(layout(8, 8)).unwrap();
+    for align in [32, 256, 4096] {
+      let memory = heap.alloc(layout(100, align)).unwrap();
+      assert_eq!(memory.as_ptr() as usize % align, 0);
+      assert!(unsafe { heap.usable_size(memory) } >= 100);
+      // the gap in front of the aligned memory block is available for further allocations
+      let filler = heap.alloc(layout(MIN_BLOCK_SIZE - HEADER_SIZE, 8)).unwrap();
+      unsafe {
+        heap.free(filler);
+        heap.free(memory);
+      }
+    }
+    unsafe { heap.free(small) };
+    assert_eq!(heap.stats().used, 0);
+    assert!(heap.alloc(largest_payload(REGION_SIZE)).is_some());
+  }
+
+  #[test]
+  fn adjacent_regions_are_merged() {
+    let mut memory = region(REGION_SIZE);
+    let start = memory.as_mut_ptr() as usize;
+    let mut heap = Heap::new();
+    unsafe {
+      heap.add_region(start, REGION_SIZE / 2);
+      heap.add_region(start + REGION_SIZE / 2, REGION_SIZE / 2);
+    }
+    assert_eq!(heap.stats().heap_size, REGION_SIZE);
+    assert!(heap.alloc(largest_payload(REGION_SIZE)).is_some());
+  }
+}
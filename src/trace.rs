@@ -0,0 +1,432 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Allocation Event Trace
+//!
+//! With the ``trace`` feature active each ``alloc``, ``free`` and ``alloc_page`` is recorded into a lock free ring
+//! buffer. Each entry holds the operation, the payload address, the requested size and alignment, the bucket, the core
+//! that has executed the operation and a timestamp taken from a pluggable clock (see [set_trace_clock]).
+//!
+//! The ring buffer keeps the latest [TRACE_ENTRIES] events. The recorded events could be drained with [drain_trace]
+//! or exported in a binary format with [write_trace] to analyze the allocation behaviour on a host machine.
+//!
+//! The binary trace format stores all values in little endian byte order. It starts with a header, the magic
+//! ``b"RPAT"`` and the [TRACE_VERSION] (2 bytes), followed by one record per event: operation (1), core (1),
+//! bucket (2), address (8), size (8), alignment (8) and timestamp (8).
+//!
+
+use crate::cpu;
+use core::fmt;
+use core::sync::atomic::{fence, AtomicU64, AtomicUsize, Ordering};
+
+/// The number of events kept in the trace ring buffer
+pub const TRACE_ENTRIES: usize = 1024;
+
+/// The magic each binary trace starts with
+pub const TRACE_MAGIC: [u8; 4] = *b"RPAT";
+
+/// The version of the binary trace format written by [write_trace]
+pub const TRACE_VERSION: u16 = 1;
+
+/// The size of a single event record in the binary trace format
+const RECORD_SIZE: usize = 36;
+
+/// The allocator operation recorded in a trace event
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TraceOp {
+  /// Memory has been allocated
+  Alloc = 1,
+  /// Memory has been freed
+  Free = 2,
+  /// Pages of memory have been allocated
+  AllocPage = 3,
+}
+
+impl TraceOp {
+  fn from_u8(value: u8) -> Option<Self> {
+    match value {
+      1 => Some(TraceOp::Alloc),
+      2 => Some(TraceOp::Free),
+      3 => Some(TraceOp::AllocPage),
+      _ => None,
+    }
+  }
+}
+
+/// A single event recorded in the trace
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TraceEvent {
+  /// The allocator operation
+  pub op: TraceOp,
+  /// The payload address allocated or freed
+  pub address: usize,
+  /// The requested payload size
+  pub size: usize,
+  /// The requested alignment or page size
+  pub align: usize,
+  /// The bucket the memory block is assigned to
+  pub bucket: usize,
+  /// The core that has executed the operation
+  pub core: usize,
+  /// The timestamp taken from the trace clock
+  pub timestamp: u64,
+}
+
+/// The result of draining the trace
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraceDrain {
+  /// The number of events drained
+  pub drained: usize,
+  /// The number of events that have been overwritten before they could be drained
+  pub lost: usize,
+}
+
+/// A single entry of the ring buffer. Its sequence number is odd while the entry is written and contains the
+/// position of the event the entry belongs to once it is completely written. This allows the reader to detect entries
+/// that are overwritten while reading them.
+struct TraceSlot {
+  seq: AtomicUsize,
+  /// operation, bucket and core packed into a single value
+  meta: AtomicUsize,
+  address: AtomicUsize,
+  size: AtomicUsize,
+  align: AtomicUsize,
+  timestamp: AtomicU64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: TraceSlot = TraceSlot {
+  seq: AtomicUsize::new(0),
+  meta: AtomicUsize::new(0),
+  address: AtomicUsize::new(0),
+  size: AtomicUsize::new(0),
+  align: AtomicUsize::new(0),
+  timestamp: AtomicU64::new(0),
+};
+
+/// The ring buffer containing the latest trace events
+static TRACE: [TraceSlot; TRACE_ENTRIES] = [EMPTY_SLOT; TRACE_ENTRIES];
+
+/// The ever increasing position of the next event to be recorded
+static WRITE_POS: AtomicUsize = AtomicUsize::new(0);
+
+/// The position of the next event to be drained
+static READ_POS: AtomicUsize = AtomicUsize::new(0);
+
+/// The clock providing the timestamps of the trace events, stored as function pointer. 0 if no clock is set
+static CLOCK: AtomicUsize = AtomicUsize::new(0);
+
+/// Set the clock providing the timestamp of each trace event, for example a function reading the system timer. As
+/// long as no clock is set all timestamps are 0.
+pub fn set_trace_clock(clock: fn() -> u64) {
+  CLOCK.store(clock as usize, Ordering::Release);
+}
+
+/// Record an allocator operation in the trace
+pub(crate) fn record(op: TraceOp, address: usize, size: usize, align: usize, bucket: usize) {
+  let timestamp = match CLOCK.load(Ordering::Acquire) {
+    0 => 0,
+    clock => {
+      let clock: fn() -> u64 = unsafe { core::mem::transmute(clock) };
+      clock()
+    }
+  };
+  // claiming the position is the only synchronisation between concurrent writers. If writers are more than
+  // TRACE_ENTRIES events apart from each other while writing the same slot the reader discards this entry
+  let pos = WRITE_POS.fetch_add(1, Ordering::AcqRel);
+  let slot = &TRACE[pos % TRACE_ENTRIES];
+  slot.seq.store(2 * pos + 1, Ordering::Relaxed);
+  fence(Ordering::Release);
+  slot.meta.store(
+    op as usize | bucket << 8 | cpu::core_id() << 24,
+    Ordering::Relaxed,
+  );
+  slot.address.store(address, Ordering::Relaxed);
+  slot.size.store(size, Ordering::Relaxed);
+  slot.align.store(align, Ordering::Relaxed);
+  slot.timestamp.store(timestamp, Ordering::Relaxed);
+  slot.seq.store(2 * pos + 2, Ordering::Release);
+}
+
+/// Read the event at the given position from the ring buffer. Returns ``None`` if the event has been overwritten
+/// already or is not completely written yet.
+fn read(pos: usize) -> Option<TraceEvent> {
+  let slot = &TRACE[pos % TRACE_ENTRIES];
+  if slot.seq.load(Ordering::Acquire) != 2 * pos + 2 {
+    return None;
+  }
+  let meta = slot.meta.load(Ordering::Relaxed);
+  let event = TraceEvent {
+    op: TraceOp::from_u8(meta as u8)?,
+    bucket: (meta >> 8) & 0xFFFF,
+    core: meta >> 24,
+    address: slot.address.load(Ordering::Relaxed),
+    size: slot.size.load(Ordering::Relaxed),
+    align: slot.align.load(Ordering::Relaxed),
+    timestamp: slot.timestamp.load(Ordering::Relaxed),
+  };
+  // if the sequence has changed while reading the event has been overwritten in between
+  fence(Ordering::Acquire);
+  if slot.seq.load(Ordering::Relaxed) != 2 * pos + 2 {
+    return None;
+  }
+
+  Some(event)
+}
+
+/// Drain all events recorded since the last drain and pass them to the given function in the order they have been
+/// recorded. Events that have been overwritten before they could be drained are counted as lost.
+///
+/// Draining the trace from several cores at the same time is not supported.
+///
+/// # Example
+/// ```ignore
+/// # use ruspiro_allocator::drain_trace;
+/// let result = drain_trace(|event| {
+///   // print the event to the console of your choice
+///   let _ = event;
+/// });
+/// ```
+pub fn drain_trace<F>(mut f: F) -> TraceDrain
+where
+  F: FnMut(&TraceEvent),
+{
+  let mut result = TraceDrain::default();
+  let end = WRITE_POS.load(Ordering::Acquire);
+  let mut pos = READ_POS.load(Ordering::Acquire);
+  // anything older than the ring buffer size is overwritten already
+  if end - pos > TRACE_ENTRIES {
+    result.lost += end - pos - TRACE_ENTRIES;
+    pos = end - TRACE_ENTRIES;
+  }
+  while pos < end {
+    match read(pos) {
+      Some(event) => {
+        result.drained += 1;
+        f(&event);
+      }
+      None => result.lost += 1,
+    }
+    pos += 1;
+  }
+  READ_POS.store(end, Ordering::Release);
+
+  result
+}
+
+/// Drain all events recorded since the last drain and export them in the binary trace format. The trace is passed in
+/// small chunks to the given function, which could forward them to the UART or a file. Returns the total number of
+/// bytes written or the first error returned by the given function. Events that could not be written are lost.
+///
+/// # Example
+/// ```ignore
+/// # use ruspiro_allocator::write_trace;
+/// let written = write_trace(|chunk| {
+///   // send the chunk to the UART of your choice
+///   let _ = chunk;
+///   Ok::<(), ()>(())
+/// });
+/// ```
+pub fn write_trace<F, E>(mut write: F) -> Result<usize, E>
+where
+  F: FnMut(&[u8]) -> Result<(), E>,
+{
+  write(&TRACE_MAGIC)?;
+  write(&TRACE_VERSION.to_le_bytes())?;
+  let mut written = TRACE_MAGIC.len() + 2;
+
+  let mut result = Ok(());
+  drain_trace(|event| {
+    if result.is_ok() {
+      result = write(&encode(event));
+      written += RECORD_SIZE;
+    }
+  });
+
+  result.map(|_| written)
+}
+
+/// Encode a single event into a record of the binary trace format
+fn encode(event: &TraceEvent) -> [u8; RECORD_SIZE] {
+  let mut record = [0; RECORD_SIZE];
+  record[0] = event.op as u8;
+  record[1] = event.core as u8;
+  record[2..4].copy_from_slice(&(event.bucket as u16).to_le_bytes());
+  record[4..12].copy_from_slice(&(event.address as u64).to_le_bytes());
+  record[12..20].copy_from_slice(&(event.size as u64).to_le_bytes());
+  record[20..28].copy_from_slice(&(event.align as u64).to_le_bytes());
+  record[28..36].copy_from_slice(&event.timestamp.to_le_bytes());
+  record
+}
+
+/// Errors that might occur while reading a binary trace
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TraceError {
+  /// The data does not start with the trace magic
+  BadMagic,
+  /// The trace has been written with a version of the format that is not supported
+  UnsupportedVersion(u16),
+  /// The trace ends in the middle of a record
+  Truncated,
+  /// The trace contains a record with an unknown operation
+  UnknownOp(u8),
+}
+
+impl fmt::Display for TraceError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TraceError::BadMagic => write!(f, "not an allocation trace"),
+      TraceError::UnsupportedVersion(version) => {
+        write!(f, "unsupported allocation trace version {}", version)
+      }
+      TraceError::Truncated => write!(f, "allocation trace is truncated"),
+      TraceError::UnknownOp(op) => {
+        write!(
+          f,
+          "allocation trace contains an unknown operation {:#04x}",
+          op
+        )
+      }
+    }
+  }
+}
+
+/// Read a binary trace written with [write_trace] from a byte slice without any heap memory allocation
+#[derive(Clone, Debug)]
+pub struct TraceReader<'a> {
+  records: &'a [u8],
+}
+
+impl<'a> TraceReader<'a> {
+  /// Read the header of the given trace
+  pub fn new(data: &'a [u8]) -> Result<Self, TraceError> {
+    if data.len() < TRACE_MAGIC.len() + 2 || data[..TRACE_MAGIC.len()] != TRACE_MAGIC {
+      return Err(TraceError::BadMagic);
+    }
+    let version = u16::from_le_bytes([data[4], data[5]]);
+    if version != TRACE_VERSION {
+      return Err(TraceError::UnsupportedVersion(version));
+    }
+
+    Ok(Self {
+      records: &data[6..],
+    })
+  }
+}
+
+impl<'a> Iterator for TraceReader<'a> {
+  type Item = Result<TraceEvent, TraceError>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.records.is_empty() {
+      return None;
+    }
+    if self.records.len() < RECORD_SIZE {
+      self.records = &[];
+      return Some(Err(TraceError::Truncated));
+    }
+    let (record, rest) = self.records.split_at(RECORD_SIZE);
+    self.records = rest;
+
+    let u64_at = |offset: usize| {
+      let mut bytes = [0; 8];
+      bytes.copy_from_slice(&record[offset..offset + 8]);
+      u64::from_le_bytes(bytes)
+    };
+    Some(
+      TraceOp::from_u8(record[0])
+        .ok_or(TraceError::UnknownOp(record[0]))
+        .map(|op| TraceEvent {
+          op,
+          core: record[1] as usize,
+          bucket: u16::from_le_bytes([record[2], record[3]]) as usize,
+          address: u64_at(4) as usize,
+          size: u64_at(12) as usize,
+          align: u64_at(20) as usize,
+          timestamp: u64_at(28),
+        }),
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// The address range of the events recorded by the tests, apart from any memory allocated by other tests
+  const TEST_ADDRESS: usize = 0x7ace_0000;
+
+  fn clock() -> u64 {
+    0x1234_5678_9abc
+  }
+
+  #[test]
+  fn round_trip() {
+    set_trace_clock(clock);
+    record(TraceOp::Alloc, TEST_ADDRESS, 100, 8, 1);
+    record(TraceOp::AllocPage, TEST_ADDRESS + 0x1000, 2, 0x1000, 64);
+    record(TraceOp::Free, TEST_ADDRESS, 0, 0, 1);
+
+    let mut trace = Vec::new();
+    let written = write_trace(|chunk| {
+      trace.extend_from_slice(chunk);
+      Ok::<(), ()>(())
+    });
+    assert_eq!(written, Ok(trace.len()));
+
+    let events: Vec<_> = TraceReader::new(&trace)
+      .unwrap()
+      .collect::<Result<Vec<_>, _>>()
+      .unwrap()
+      .into_iter()
+      .filter(|event| event.address & !0xFFFF == TEST_ADDRESS)
+      .collect();
+    let event = |op, address, size, align, bucket| TraceEvent {
+      op,
+      address,
+      size,
+      align,
+      bucket,
+      core: 0,
+      timestamp: clock(),
+    };
+    assert_eq!(
+      events,
+      [
+        event(TraceOp::Alloc, TEST_ADDRESS, 100, 8, 1),
+        event(TraceOp::AllocPage, TEST_ADDRESS + 0x1000, 2, 0x1000, 64),
+        event(TraceOp::Free, TEST_ADDRESS, 0, 0, 1),
+      ]
+    );
+
+    // the truncated last record is reported once
+    let mut reader = TraceReader::new(&trace[..trace.len() - 1]).unwrap();
+    assert_eq!(reader.by_ref().last(), Some(Err(TraceError::Truncated)));
+    assert_eq!(reader.next(), None);
+  }
+
+  #[test]
+  fn invalid_trace() {
+    assert_eq!(
+      TraceReader::new(b"RPHS\x01\x00").err(),
+      Some(TraceError::BadMagic)
+    );
+    assert_eq!(TraceReader::new(b"RPAT").err(), Some(TraceError::BadMagic));
+    assert_eq!(
+      TraceReader::new(b"RPAT\x02\x00").err(),
+      Some(TraceError::UnsupportedVersion(2))
+    );
+
+    let mut trace = Vec::from(&TRACE_MAGIC[..]);
+    trace.extend_from_slice(&TRACE_VERSION.to_le_bytes());
+    trace.extend_from_slice(&[0x07; RECORD_SIZE]);
+    let mut reader = TraceReader::new(&trace).unwrap();
+    assert_eq!(reader.next(), Some(Err(TraceError::UnknownOp(0x07))));
+    assert_eq!(reader.next(), None);
+  }
+}
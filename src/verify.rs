@@ -0,0 +1,386 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Heap Integrity Verification
+//!
+//! Walk the whole HEAP block by block and all lists of re-usable memory blocks to verify the consistency of the
+//! administrative data maintained by the allocator.
+//!
+//! Only the bucket allocator maintains such data, so verifying the HEAP is not available with the ``buddy`` or
+//! ``tlsf`` feature.
+//!
+
+use crate::memory::{
+  self, MemoryDescriptor, FREE_BUCKETS, GUARD_SIZE, LINK_OFFSET, MM_FREE_MAGIC, MM_MAGIC,
+};
+use core::fmt;
+use core::sync::atomic::Ordering;
+
+/// The maximum number of violations stored in a [HeapReport]. Any further violation is only counted.
+pub const MAX_VIOLATIONS: usize = 16;
+
+/// A single inconsistency found while verifying the HEAP
+#[derive(Copy, Clone, Debug)]
+pub enum HeapViolation {
+  /// The memory block does not carry a known magic. The HEAP could not be walked any further behind this block
+  BadMagic { block: usize, magic: u32 },
+  /// The memory block is assigned to a bucket that does not exist
+  BadBucket { block: usize, bucket: usize },
+  /// The size of the memory block does not match its bucket or does not fit into the HEAP. The HEAP could not be
+  /// walked any further behind this block if the size does not fit into the HEAP
+  BadSize {
+    block: usize,
+    bucket: usize,
+    size: usize,
+  },
+  /// The payload of the live memory block does not fit into the memory block or could not be linked back to it
+  BadPayload { block: usize, payload: usize },
+  /// The guard bytes of the live memory block have been overwritten
+  #[cfg(feature = "guard-bytes")]
+  Guard(crate::GuardViolation),
+  /// The freed memory block has been written to after it was freed
+  #[cfg(feature = "poison")]
+  Poison(crate::PoisonViolation),
+  /// The entry of a free bucket list is located outside the HEAP
+  FreeOutOfHeap { bucket: usize, block: usize },
+  /// The free bucket list contains more entries than freed memory blocks exist on the HEAP, so it most likely
+  /// contains a cycle
+  FreeCycle { bucket: usize, block: usize },
+  /// The entry of a free bucket list is not marked as a freed memory block
+  FreeNotFreed {
+    bucket: usize,
+    block: usize,
+    magic: u32,
+  },
+  /// The entry of a free bucket list is assigned to a different bucket or size class
+  FreeWrongBucket {
+    bucket: usize,
+    block: usize,
+    block_bucket: usize,
+    size: usize,
+  },
+  /// The link to the preceding entry of a free bucket list does not match the actual preceding entry
+  FreeBrokenLink {
+    bucket: usize,
+    block: usize,
+    prev: usize,
+    expected: usize,
+  },
+  /// The tail of a free bucket list does not point to its last entry
+  FreeBadTail {
+    bucket: usize,
+    tail: usize,
+    last: usize,
+  },
+  /// The number of freed memory blocks found on the HEAP does not match the number of memory blocks waiting for
+  /// re-usage
+  FreeCountMismatch { on_heap: usize, listed: usize },
+}
+
+impl fmt::Display for HeapViolation {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match *self {
+      HeapViolation::BadMagic { block, magic } => {
+        write!(
+          f,
+          "memory block {:#x} has an unknown magic {:#x}",
+          block, magic
+        )
+      }
+      HeapViolation::BadBucket { block, bucket } => {
+        write!(
+          f,
+          "memory block {:#x} is assigned to the unknown bucket {}",
+          block, bucket
+        )
+      }
+      HeapViolation::BadSize {
+        block,
+        bucket,
+        size,
+      } => write!(
+        f,
+        "memory block {:#x} has a size of {:#x} that does not match its bucket {}",
+        block, size, bucket
+      ),
+      HeapViolation::BadPayload { block, payload } => write!(
+        f,
+        "memory block {:#x} has an invalid payload address {:#x}",
+        block, payload
+      ),
+      #[cfg(feature = "guard-bytes")]
+      HeapViolation::Guard(ref violation) => violation.fmt(f),
+      #[cfg(feature = "poison")]
+      HeapViolation::Poison(ref violation) => violation.fmt(f),
+      HeapViolation::FreeOutOfHeap { bucket, block } => write!(
+        f,
+        "free bucket {} contains the memory block {:#x} located outside the HEAP",
+        bucket, block
+      ),
+      HeapViolation::FreeCycle { bucket, block } => write!(
+        f,
+        "free bucket {} contains a cycle at memory block {:#x}",
+        bucket, block
+      ),
+      HeapViolation::FreeNotFreed {
+        bucket,
+        block,
+        magic,
+      } => write!(
+        f,
+        "free bucket {} contains the memory block {:#x} that is not freed (magic {:#x})",
+        bucket, block, magic
+      ),
+      HeapViolation::FreeWrongBucket {
+        bucket,
+        block,
+        block_bucket,
+        size,
+      } => write!(
+        f,
+        "free bucket {} contains the memory block {:#x} of bucket {} with size {:#x}",
+        bucket, block, block_bucket, size
+      ),
+      HeapViolation::FreeBrokenLink {
+        bucket,
+        block,
+        prev,
+        expected,
+      } => write!(
+        f,
+        "free bucket {} contains the memory block {:#x} linked to {:#x} instead of {:#x}",
+        bucket, block, prev, expected
+      ),
+      HeapViolation::FreeBadTail { bucket, tail, last } => write!(
+        f,
+        "free bucket {} has the tail {:#x} but ends with {:#x}",
+        bucket, tail, last
+      ),
+      HeapViolation::FreeCountMismatch { on_heap, listed } => write!(
+        f,
+        "{} freed memory blocks found on the HEAP but {} waiting for re-usage",
+        on_heap, listed
+      ),
+    }
+  }
+}
+
+/// The result of [verify_heap]
+#[derive(Copy, Clone, Debug)]
+pub struct HeapReport {
+  /// The start address of the HEAP
+  pub heap_start: usize,
+  /// The address of the next free memory location at the end of the HEAP
+  pub heap_end: usize,
+  /// The number of live memory blocks found on the HEAP
+  pub live_blocks: usize,
+  /// The number of freed memory blocks found on the HEAP
+  pub free_blocks: usize,
+  /// The number of violations found, this might be more than stored in this report
+  pub violation_count: usize,
+  violations: [Option<HeapViolation>; MAX_VIOLATIONS],
+}
+
+impl HeapReport {
+  /// Returns whether no violation has been found
+  pub fn is_ok(&self) -> bool {
+    self.violation_count == 0
+  }
+
+  /// Iterate over the violations stored in this report
+  pub fn violations(&self) -> impl Iterator<Item = &HeapViolation> {
+    self.violations.iter().flatten()
+  }
+
+  fn add(&mut self, violation: HeapViolation) {
+    if let Some(slot) = self.violations.get_mut(self.violation_count) {
+      *slot = Some(violation);
+    }
+    self.violation_count += 1;
+  }
+}
+
+/// Verify the consistency of the HEAP. This walks every memory block from the start of the HEAP to its current end
+/// and checks the magic, size and bucket of each of them. Afterwards each entry of the free bucket lists is verified
+/// to be a freed memory block of the corresponding size class that is properly linked to its neighbours.
+///
+/// This gives only a consistent picture if no other core is allocating or freeing memory while verifying the HEAP.
+///
+/// # Example
+/// ```ignore
+/// # use ruspiro_allocator::verify_heap;
+/// let report = verify_heap();
+/// for violation in report.violations() {
+///   // print the violation to the console of your choice
+///   let _ = violation;
+/// }
+/// assert!(report.is_ok());
+/// ```
+pub fn verify_heap() -> HeapReport {
+  let mut report = HeapReport {
+    heap_start: memory::heap_bottom(),
+    heap_end: memory::heap_top(),
+    live_blocks: 0,
+    free_blocks: 0,
+    violation_count: 0,
+    violations: [None; MAX_VIOLATIONS],
+  };
+
+  // if the HEAP could not be walked completely the number of freed memory blocks found is not reliable
+  let complete = verify_blocks(&mut report);
+  let listed = verify_free_buckets(&mut report, complete);
+  if complete && listed != report.free_blocks {
+    report.add(HeapViolation::FreeCountMismatch {
+      on_heap: report.free_blocks,
+      listed,
+    });
+  }
+
+  report
+}
+
+/// Walk the HEAP and verify each memory block. Returns whether the whole HEAP could be walked.
+fn verify_blocks(report: &mut HeapReport) -> bool {
+  let heap_end = report.heap_end;
+  let mut complete = true;
+  let _ = memory::walk_heap(|block, descriptor| {
+    let (bucket, size) = (descriptor.bucket, descriptor.size);
+    match descriptor.magic {
+      MM_MAGIC => report.live_blocks += 1,
+      MM_FREE_MAGIC => report.free_blocks += 1,
+      magic => {
+        report.add(HeapViolation::BadMagic { block, magic });
+        complete = false;
+        return false;
+      }
+    }
+
+    if bucket >= FREE_BUCKETS.len() {
+      report.add(HeapViolation::BadBucket { block, bucket });
+    }
+    let size_fits = size >= core::mem::size_of::<MemoryDescriptor>() && size <= heap_end - block;
+    let size_mismatch =
+      matches!(memory::bucket_size(bucket), Some(bucket_size) if bucket_size != size);
+    if !size_fits || size_mismatch {
+      report.add(HeapViolation::BadSize {
+        block,
+        bucket,
+        size,
+      });
+    }
+    if !size_fits {
+      complete = false;
+      return false;
+    }
+
+    if descriptor.magic == MM_MAGIC {
+      verify_live_block(report, block, descriptor);
+    }
+
+    true
+  });
+
+  complete
+}
+
+/// Verify the payload of a live memory block
+fn verify_live_block(report: &mut HeapReport, block: usize, descriptor: &MemoryDescriptor) {
+  let payload = descriptor.payload_addr;
+  let payload_fits = payload >= block + core::mem::size_of::<MemoryDescriptor>() + GUARD_SIZE
+    && matches!(
+      payload.checked_add(descriptor.req_size + GUARD_SIZE),
+      Some(payload_end) if payload_end <= block + descriptor.size
+    );
+  if !payload_fits || unsafe { *((payload - LINK_OFFSET) as *const usize) } != block {
+    report.add(HeapViolation::BadPayload { block, payload });
+  } else {
+    #[cfg(feature = "guard-bytes")]
+    if let Some(violation) = crate::guard::check(block, descriptor) {
+      report.add(HeapViolation::Guard(violation));
+    }
+  }
+}
+
+/// Verify the entries of all free bucket lists. Returns the number of freed memory blocks waiting for re-usage.
+fn verify_free_buckets(report: &mut HeapReport, complete: bool) -> usize {
+  #[allow(unused_mut)]
+  let mut listed = 0;
+  #[cfg(feature = "quarantine")]
+  {
+    listed += crate::quarantine::count();
+  }
+
+  // a list containing more entries than freed memory blocks on the HEAP must contain a cycle. If the HEAP could not be
+  // walked completely the number of freed memory blocks is not known, so the size of the HEAP bounds the lists
+  let max_listed = if complete {
+    report.free_blocks
+  } else {
+    memory::max_heap_blocks()
+  };
+  for (bucket, queue) in FREE_BUCKETS.iter().enumerate() {
+    let mut expected_prev = 0;
+    let mut block = queue.head.load(Ordering::Acquire);
+    while block != 0 {
+      if !memory::within_heap(block) {
+        report.add(HeapViolation::FreeOutOfHeap { bucket, block });
+        break;
+      }
+      if listed >= max_listed {
+        report.add(HeapViolation::FreeCycle { bucket, block });
+        break;
+      }
+      listed += 1;
+
+      let descriptor = unsafe { *(block as *const MemoryDescriptor) };
+      let (magic, block_bucket, size) = (descriptor.magic, descriptor.bucket, descriptor.size);
+      if magic != MM_FREE_MAGIC {
+        report.add(HeapViolation::FreeNotFreed {
+          bucket,
+          block,
+          magic,
+        });
+        break;
+      }
+      if block_bucket != bucket
+        || matches!(memory::bucket_size(bucket), Some(bucket_size) if bucket_size != size)
+      {
+        report.add(HeapViolation::FreeWrongBucket {
+          bucket,
+          block,
+          block_bucket,
+          size,
+        });
+      }
+      if descriptor.prev != expected_prev {
+        report.add(HeapViolation::FreeBrokenLink {
+          bucket,
+          block,
+          prev: descriptor.prev,
+          expected: expected_prev,
+        });
+      }
+      #[cfg(feature = "poison")]
+      if let Some(violation) = crate::poison::check_freed(block, &descriptor) {
+        report.add(HeapViolation::Poison(violation));
+      }
+
+      expected_prev = block;
+      block = descriptor.next;
+    }
+
+    let tail = queue.tail.load(Ordering::Acquire);
+    if block == 0 && tail != expected_prev {
+      report.add(HeapViolation::FreeBadTail {
+        bucket,
+        tail,
+        last: expected_prev,
+      });
+    }
+  }
+
+  listed
+}
@@ -0,0 +1,218 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Watermarks
+//!
+//! With the ``watermarks`` feature thresholds could be set in percent of the HEAP region, like 75% and 90%. After each
+//! allocation and free the size of the HEAP or the bytes in use are compared to them and a callback is called once a
+//! threshold is crossed in either direction. This allows a supervisor to shed load before the HEAP is exhausted. The
+//! highest value seen is kept as high-water mark.
+//!
+//! The callback is not called again as long as the value stays between the same thresholds, it might allocate memory
+//! itself.
+//!
+
+use crate::backend::{Backend, BACKEND};
+use crate::lock::SpinLock;
+use crate::memory;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// The maximum number of thresholds that could be set
+pub const MAX_WATERMARKS: usize = 4;
+
+/// The value compared to the thresholds
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WatermarkMetric {
+  /// The size of the HEAP, which grows as long as no freed memory could be re-used. For the bucket allocator this is
+  /// the distance of ``HEAP_START`` to the start of the HEAP region
+  HeapSize,
+  /// The memory occupied by live allocations
+  Used,
+}
+
+/// A threshold that has been crossed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WatermarkEvent {
+  /// The threshold in percent of the HEAP region
+  pub threshold: u8,
+  /// Whether the value rose above the threshold, otherwise it fell below
+  pub rising: bool,
+  /// The current value of the metric in Bytes
+  pub value: usize,
+  /// The size of the HEAP region in Bytes
+  pub region: usize,
+}
+
+/// The callback called once a threshold is crossed
+pub type WatermarkCallback = fn(WatermarkEvent);
+
+/// The reasons the thresholds could not be set
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WatermarkError {
+  /// No threshold or more than [MAX_WATERMARKS] thresholds are given
+  Count(usize),
+  /// The thresholds are not strictly increasing percentages between 1 and 100
+  Threshold(u8),
+}
+
+impl fmt::Display for WatermarkError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      WatermarkError::Count(count) => write!(
+        f,
+        "between 1 and {} thresholds are supported, got {}",
+        MAX_WATERMARKS, count
+      ),
+      WatermarkError::Threshold(threshold) => write!(
+        f,
+        "the threshold {}% is not an increasing percentage between 1 and 100",
+        threshold
+      ),
+    }
+  }
+}
+
+/// The thresholds set together with the metric and the callback
+#[derive(Copy, Clone)]
+struct Watermarks {
+  metric: WatermarkMetric,
+  thresholds: [u8; MAX_WATERMARKS],
+  count: usize,
+  callback: WatermarkCallback,
+}
+
+impl Watermarks {
+  /// The current value of the metric
+  fn value(&self) -> usize {
+    let stats = BACKEND.stats();
+    match self.metric {
+      WatermarkMetric::HeapSize => stats.heap_size,
+      WatermarkMetric::Used => stats.used,
+    }
+  }
+
+  /// The number of thresholds the given value of the given HEAP region reached
+  fn level(&self, value: usize, region: usize) -> usize {
+    self.thresholds[..self.count]
+      .iter()
+      .take_while(|&&threshold| value as u128 * 100 >= threshold as u128 * region as u128)
+      .count()
+  }
+}
+
+static WATERMARKS: SpinLock<Option<Watermarks>> = SpinLock::new(None);
+
+/// Whether thresholds are set, so the checks could skip the lock otherwise
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The number of thresholds reached by the last check
+static LEVEL: AtomicUsize = AtomicUsize::new(0);
+
+/// The highest value of the metric seen
+static HIGH_WATER_MARK: AtomicUsize = AtomicUsize::new(0);
+
+/// Set the thresholds in percent of the HEAP region and the callback called once the given metric crosses any of
+/// them. The thresholds already reached are not reported, the high-water mark starts with the current value.
+///
+/// # Example
+/// ```ignore
+/// use ruspiro_allocator::{set_watermarks, WatermarkEvent, WatermarkMetric};
+///
+/// fn heap_alarm(event: WatermarkEvent) {
+///   if event.rising {
+///     SUPERVISOR.shed_load(event.threshold);
+///   }
+/// }
+///
+/// set_watermarks(WatermarkMetric::Used, &[75, 90], heap_alarm).unwrap();
+/// ```
+pub fn set_watermarks(
+  metric: WatermarkMetric,
+  thresholds: &[u8],
+  callback: WatermarkCallback,
+) -> Result<(), WatermarkError> {
+  if thresholds.is_empty() || thresholds.len() > MAX_WATERMARKS {
+    return Err(WatermarkError::Count(thresholds.len()));
+  }
+  let mut previous = 0;
+  for &threshold in thresholds {
+    if threshold <= previous || threshold > 100 {
+      return Err(WatermarkError::Threshold(threshold));
+    }
+    previous = threshold;
+  }
+  let mut watermarks = Watermarks {
+    metric,
+    thresholds: [0; MAX_WATERMARKS],
+    count: thresholds.len(),
+    callback,
+  };
+  watermarks.thresholds[..thresholds.len()].copy_from_slice(thresholds);
+
+  let value = watermarks.value();
+  let mut current = WATERMARKS.lock();
+  LEVEL.store(watermarks.level(value, region()), Ordering::Release);
+  HIGH_WATER_MARK.store(value, Ordering::Release);
+  *current = Some(watermarks);
+  ENABLED.store(true, Ordering::Release);
+  Ok(())
+}
+
+/// Remove the thresholds, the callback is not called any more
+pub fn clear_watermarks() {
+  let mut current = WATERMARKS.lock();
+  ENABLED.store(false, Ordering::Release);
+  *current = None;
+}
+
+/// The highest value of the metric seen since the thresholds have been set
+pub fn high_water_mark() -> usize {
+  HIGH_WATER_MARK.load(Ordering::Acquire)
+}
+
+/// The size of the HEAP region the thresholds refer to
+fn region() -> usize {
+  memory::heap_limit().saturating_sub(memory::heap_bottom())
+}
+
+/// Compare the metric to the thresholds after the HEAP usage has changed and call the callback for each threshold
+/// crossed since the last check. This shall not be called while holding the lock of an allocator backend.
+///
+/// As this runs with each allocation, also of an interrupt handler, it never waits for the lock of the thresholds. If
+/// they are in use the check is skipped and a crossing is reported by the next check.
+pub(crate) fn check() {
+  if !ENABLED.load(Ordering::Acquire) {
+    return;
+  }
+  // the callback is called without holding the lock, so it is able to allocate and to change the thresholds
+  let watermarks = match WATERMARKS.try_lock().as_deref() {
+    Some(Some(watermarks)) => *watermarks,
+    _ => return,
+  };
+  let value = watermarks.value();
+  let region = region();
+  HIGH_WATER_MARK.fetch_max(value, Ordering::AcqRel);
+  // swapping the level ensures each crossing is reported only once, even if several cores check at the same time
+  let level = watermarks.level(value, region);
+  let previous = LEVEL.swap(level, Ordering::AcqRel);
+  let report = |index: usize, rising: bool| {
+    (watermarks.callback)(WatermarkEvent {
+      threshold: watermarks.thresholds[index],
+      rising,
+      value,
+      region,
+    })
+  };
+  if level > previous {
+    (previous..level).for_each(|index| report(index, true));
+  } else {
+    (level..previous)
+      .rev()
+      .for_each(|index| report(index, false));
+  }
+}